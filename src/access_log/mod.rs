@@ -0,0 +1,332 @@
+mod writer;
+
+pub use writer::Rotation;
+
+use crate::{
+    AppState, auth::Subject, ip_filter::ClientIp, limits::parse_bytes, listener::Connection,
+    metrics, request_context::X_REQUEST_ID, rules::Rewritten,
+};
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::Response as AxumResponse,
+};
+use clap::ValueEnum;
+use core::{fmt::Write as _, str::FromStr};
+use http_body::{Frame, SizeHint};
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        mpsc::{SyncSender, TrySendError},
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339, macros::format_description};
+
+/// Query parameters that carry credentials and are never written to the access log.
+const REDACTED_PARAMS: &[&str] = &[
+    "signature",
+    "token",
+    "access_token",
+    "x-amz-signature",
+    "x-amz-credential",
+    "x-amz-security-token",
+];
+
+/// The format access log lines are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AccessLogFormat {
+    /// The Apache Combined Log Format, followed by `key=value` pairs of the extra fields.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// A number of bytes, e.g. `100MiB`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_bytes(s).map(|bytes| Self(bytes as u64))
+    }
+}
+
+/// Writes a line to the access log for every response once its body has been sent.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    /// Start writing the access log to `path`, or stdout if there isn't one.
+    pub fn new(
+        format: AccessLogFormat,
+        path: Option<PathBuf>,
+        rotation: Rotation,
+    ) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            format,
+            lines: writer::spawn(path, rotation)?,
+        }))
+    }
+}
+
+/// Whether a conditional request was answered from the client's cache.
+#[derive(Debug, Clone, Copy)]
+enum CacheOutcome {
+    /// The client's cached copy was current, so no body was sent.
+    Hit,
+    /// The client's cached copy was stale, so the file was sent in full.
+    Miss,
+}
+
+impl CacheOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheOutcome::Hit => "hit",
+            CacheOutcome::Miss => "miss",
+        }
+    }
+}
+
+/// Everything logged about a request, completed once its response body has been sent.
+struct Entry {
+    time: OffsetDateTime,
+    start: Instant,
+    client_ip: Option<String>,
+    identity: Option<Arc<str>>,
+    method: String,
+    path: String,
+    query: Option<String>,
+    version: String,
+    status: StatusCode,
+    bytes: u64,
+    backend: &'static str,
+    cache: Option<CacheOutcome>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    fn format(&self, format: AccessLogFormat) -> String {
+        let duration = self.start.elapsed().as_secs_f64();
+        match format {
+            AccessLogFormat::Combined => {
+                let mut line = String::new();
+                let _ = write!(
+                    line,
+                    "{} - {} [{}] \"{} {}{}{} {}\" {} ",
+                    self.client_ip.as_deref().unwrap_or("-"),
+                    escape(self.identity.as_deref().unwrap_or("-")),
+                    self.time
+                        .format(format_description!(
+                            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+                        ))
+                        .unwrap_or_default(),
+                    escape(&self.method),
+                    escape(&self.path),
+                    if self.query.is_some() { "?" } else { "" },
+                    escape(self.query.as_deref().unwrap_or_default()),
+                    self.version,
+                    self.status.as_u16(),
+                );
+                match self.bytes {
+                    0 => line.push('-'),
+                    bytes => {
+                        let _ = write!(line, "{bytes}");
+                    }
+                }
+                let _ = writeln!(
+                    line,
+                    " \"{}\" \"{}\" rt={duration:.3} backend={} cache={} request_id={}",
+                    escape(self.referer.as_deref().unwrap_or("-")),
+                    escape(self.user_agent.as_deref().unwrap_or("-")),
+                    self.backend,
+                    self.cache.map_or("-", CacheOutcome::as_str),
+                    escape(self.request_id.as_deref().unwrap_or("-")),
+                );
+                line
+            }
+            AccessLogFormat::Json => {
+                let mut line = serde_json::json!({
+                    "time": self.time.format(&Rfc3339).unwrap_or_default(),
+                    "client_ip": self.client_ip,
+                    "identity": self.identity.as_deref(),
+                    "method": self.method,
+                    "path": self.path,
+                    "query": self.query,
+                    "version": self.version,
+                    "status": self.status.as_u16(),
+                    "bytes": self.bytes,
+                    "duration": duration,
+                    "backend": self.backend,
+                    "cache": self.cache.map(CacheOutcome::as_str),
+                    "referer": self.referer,
+                    "user_agent": self.user_agent,
+                    "request_id": self.request_id,
+                })
+                .to_string();
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+/// Escape quotes, backslashes and control characters in a Combined Log Format field.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for char in value.chars() {
+        match char {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            char if char.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", char as u32);
+            }
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// Replace the values of query parameters that carry credentials.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| {
+            let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+            let decoded = percent_encoding::percent_decode_str(name).decode_utf8_lossy();
+            match REDACTED_PARAMS
+                .iter()
+                .any(|redacted| decoded.eq_ignore_ascii_case(redacted))
+            {
+                true => format!("{name}=REDACTED"),
+                false => pair.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+/// A response body that writes its request's access log entry once it has been sent or dropped.
+struct LoggedBody {
+    body: Body,
+    entry: Entry,
+    log: Arc<AccessLog>,
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // Lines are dropped rather than blocking the runtime when the writer can't keep up.
+        let line = self.entry.format(self.log.format);
+        if let Err(TrySendError::Full(_)) = self.log.lines.try_send(line) {
+            metrics::record_dropped_access_log_line();
+        }
+    }
+}
+
+impl http_body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.entry.bytes += data.len() as u64;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Middleware that writes an access log entry for every request.
+pub async fn log(State(state): State<AppState>, req: Request, next: Next) -> AxumResponse {
+    let Some(log) = state.access_log.clone() else {
+        return next.run(req).await;
+    };
+    let time = OffsetDateTime::now_utc();
+    let start = Instant::now();
+    let peer_ip = req
+        .extensions()
+        .get::<ConnectInfo<Connection>>()
+        .map(|ConnectInfo(connection)| connection.remote_addr.ip().to_canonical());
+    let conditional = req.headers().contains_key(header::IF_NONE_MATCH)
+        || req.headers().contains_key(header::IF_MODIFIED_SINCE);
+    let method = req.method().to_string();
+    let path = req.uri().path().to_owned();
+    let query = req.uri().query().map(redact_query);
+    let version = format!("{:?}", req.version());
+    let referer = header_string(req.headers(), header::REFERER);
+    let user_agent = header_string(req.headers(), header::USER_AGENT);
+    let request_id = header_string(req.headers(), X_REQUEST_ID);
+
+    let (parts, body) = next.run(req).await.into_parts();
+    // Requests are logged with the path they were made for, but served from the one they were
+    // rewritten to.
+    let served_path = parts
+        .extensions
+        .get::<Rewritten>()
+        .map_or(path.as_str(), |Rewritten(uri)| uri.path());
+    let backend = state
+        .mounts
+        .resolve(&percent_encoding::percent_decode_str(served_path).decode_utf8_lossy())
+        .map_or("-", |(mount, _)| mount.storage.name());
+    let client_ip = parts
+        .extensions
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| *ip)
+        .or(peer_ip);
+    let cache = match (conditional, parts.status) {
+        (true, StatusCode::NOT_MODIFIED) => Some(CacheOutcome::Hit),
+        (true, status) if status.is_success() => Some(CacheOutcome::Miss),
+        _ => None,
+    };
+    let entry = Entry {
+        time,
+        start,
+        client_ip: client_ip.map(|ip| ip.to_string()),
+        identity: parts
+            .extensions
+            .get::<Subject>()
+            .map(|Subject(subject)| subject.clone()),
+        method,
+        path,
+        query,
+        version,
+        status: parts.status,
+        bytes: 0,
+        backend,
+        cache,
+        referer,
+        user_agent,
+        request_id,
+    };
+    AxumResponse::from_parts(parts, Body::new(LoggedBody { body, entry, log }))
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// When a log file is rotated and how many rotated files are kept.
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub interval: Option<Duration>,
+    pub max_files: usize,
+}
+
+/// A log file that is renamed to `<path>.1` (shifting older files up to `<path>.<max files>`) and
+/// replaced once it grows too large or old.
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation) -> Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation,
+            file: BufWriter::new(file),
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        let too_large = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + incoming as u64 > max_size);
+        let too_old = self
+            .rotation
+            .interval
+            .is_some_and(|interval| self.opened.elapsed() >= interval);
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+        if self.rotation.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.rotation.max_files).rev() {
+                match fs::rename(rotated(index), rotated(index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Write a whole line, rotating the file first if needed so lines are never split across files.
+    fn write(&mut self, line: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(line.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(line.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The number of lines that may wait to be written, after which further lines are dropped.
+const BUFFERED_LINES: usize = 8192;
+
+/// Start writing lines sent through the returned channel to `path`, or stdout if there isn't one,
+/// on a dedicated thread so slow disks never block requests.
+pub fn spawn(path: Option<PathBuf>, rotation: Rotation) -> Result<SyncSender<String>> {
+    let (sender, receiver) = mpsc::sync_channel::<String>(BUFFERED_LINES);
+    let output: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(
+            RotatingFile::open(path.clone(), rotation)
+                .with_context(|| format!("Failed to open access log {path:?}"))?,
+        ),
+        None => Box::new(io::stdout()),
+    };
+    std::thread::spawn(move || drain(receiver, output));
+    Ok(sender)
+}
+
+/// Write every line received until all senders are dropped, flushing whenever no more lines are
+/// waiting.
+fn drain(receiver: Receiver<String>, mut output: Box<dyn Write + Send>) {
+    while let Ok(line) = receiver.recv() {
+        let mut result = output.write_all(line.as_bytes());
+        while let Ok(line) = receiver.try_recv() {
+            result = result.and(output.write_all(line.as_bytes()));
+        }
+        if let Err(err) = result.and(output.flush()) {
+            error!("Failed to write access log: {err}");
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use super::Scope;
+use anyhow::{Context, Result, bail};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use std::collections::HashMap;
+
+/// A user from an htpasswd file and the scope it grants.
+pub struct User {
+    pub hash: Box<str>,
+    pub scope: Scope,
+}
+
+/// Parse an Apache-style htpasswd file with one `user:hash` entry per line, optionally followed by
+/// `:read|write:path globs` to scope the user's access.
+///
+/// Only bcrypt (`$2y$`, `$2b$`, `$2a$`) and argon2 (`$argon2id$`, ...) hashes are supported.
+pub fn parse(contents: &str) -> Result<HashMap<Box<str>, User>> {
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(':');
+        let (Some(username), Some(hash)) = (fields.next(), fields.next()) else {
+            bail!("Expected 'user:hash' on line {}", number + 1);
+        };
+        if !(hash.starts_with("$2") || hash.starts_with("$argon2")) {
+            bail!(
+                "Unsupported hash for user {username:?} on line {}, only bcrypt and argon2 are supported",
+                number + 1
+            );
+        }
+        let scope = Scope::parse(fields.next(), fields.next())
+            .with_context(|| format!("Invalid scope on line {}", number + 1))?;
+        users.insert(
+            username.into(),
+            User {
+                hash: hash.into(),
+                scope,
+            },
+        );
+    }
+    Ok(users)
+}
+
+/// Check a password against a bcrypt or argon2 hash.
+pub fn verify(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
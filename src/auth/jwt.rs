@@ -0,0 +1,123 @@
+use super::{Access, Scope};
+use anyhow::{Context, Result, bail};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation,
+    jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm, PublicKeyUse},
+};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+/// A key from a JWKS file and the only algorithm tokens verified with it may use.
+pub struct Key {
+    id: Option<Box<str>>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The claims of a token that describe what it grants access to.
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    /// The path globs the token may access, each also covering everything beneath it.
+    #[serde(default)]
+    paths: Vec<String>,
+    /// The operations the token may perform on those paths.
+    #[serde(default = "default_ops")]
+    ops: Vec<Access>,
+}
+
+fn default_ops() -> Vec<Access> {
+    vec![Access::Read]
+}
+
+/// A verified token.
+pub struct Identity {
+    pub subject: Option<String>,
+    pub scope: Scope,
+}
+
+/// Parse a JWKS file, keeping the signing keys usable with HS256, RS256 or EdDSA (Ed25519).
+///
+/// Keys for other algorithms are skipped so a JWKS shared with other services can be used as-is.
+pub fn parse(contents: &str) -> Result<Vec<Key>> {
+    let set = serde_json::from_str::<JwkSet>(contents).context("Expected a JSON Web Key Set")?;
+    let mut keys = Vec::new();
+    for jwk in set.keys {
+        let id = jwk.common.key_id.as_deref();
+        if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+            continue;
+        }
+        let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+            (AlgorithmParameters::OctetKey(_), None | Some(KeyAlgorithm::HS256)) => {
+                Algorithm::HS256
+            }
+            (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+            (AlgorithmParameters::OctetKeyPair(params), None | Some(KeyAlgorithm::EdDSA))
+                if params.curve == EllipticCurve::Ed25519 =>
+            {
+                Algorithm::EdDSA
+            }
+            _ => {
+                warn!("Skipping JWKS key {id:?} as only HS256, RS256 and EdDSA keys are supported");
+                continue;
+            }
+        };
+        keys.push(Key {
+            id: id.map(Into::into),
+            algorithm,
+            key: DecodingKey::from_jwk(&jwk).with_context(|| format!("Invalid JWKS key {id:?}"))?,
+        });
+    }
+    if keys.is_empty() {
+        bail!("The JWKS doesn't contain any HS256, RS256 or EdDSA keys");
+    }
+    Ok(keys)
+}
+
+/// Verify a token against the keys matching its key ID, or every key if it doesn't have one.
+///
+/// Tokens must not have expired and, if configured, must have been issued by `issuer` for `audience`.
+pub fn verify(
+    keys: &[Key],
+    token: &str,
+    issuer: Option<&str>,
+    audience: Option<&str>,
+) -> Option<Identity> {
+    let header = jsonwebtoken::decode_header(token).ok()?;
+    let mut validation = Validation::new(header.alg);
+    let mut required = vec!["exp"];
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+        required.push("iss");
+    }
+    match audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        None => validation.validate_aud = false,
+    }
+    validation.set_required_spec_claims(&required);
+    let claims = keys
+        .iter()
+        .filter(|key| key.algorithm == header.alg)
+        .filter(|key| header.kid.is_none() || key.id.as_deref() == header.kid.as_deref())
+        .find_map(|key| jsonwebtoken::decode::<Claims>(token, &key.key, &validation).ok())?
+        .claims;
+
+    let access = claims.ops.iter().max().copied();
+    let paths = match access {
+        Some(_) => claims.paths.iter().map(String::as_str).collect(),
+        None => Vec::new(),
+    };
+    match Scope::new(access.unwrap_or(Access::Read), &paths) {
+        Ok(scope) => Some(Identity {
+            subject: claims.sub,
+            scope,
+        }),
+        Err(err) => {
+            debug!("Rejected a token with invalid path claims: {err:#}");
+            None
+        }
+    }
+}
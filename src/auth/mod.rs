@@ -0,0 +1,625 @@
+mod htpasswd;
+mod jwt;
+mod signed;
+mod tokens;
+
+pub use signed::{COOKIE_NAME, Grant, SigningKey};
+
+use crate::{
+    AppState,
+    ip_filter::ClientIp,
+    listener::Connection,
+    routes::{destination_path, problem_response},
+};
+use anyhow::{Context, Result, bail};
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use clap::ValueEnum;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use signed::Verification;
+use std::{
+    collections::HashMap,
+    iter,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tracing::{Span, debug, info, warn};
+
+/// How often credential files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The kind of access a credential grants, write access implies read access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The access granted to requests without credentials when authentication is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnonymousAccess {
+    None,
+    Read,
+}
+
+impl Access {
+    /// The access needed to make a request with the given method.
+    pub fn required_for(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => Access::Read,
+            _ => Access::Write,
+        }
+    }
+}
+
+/// The paths a credential may access and how.
+pub struct Scope {
+    access: Access,
+    paths: GlobSet,
+}
+
+impl Scope {
+    /// Parse a scope from an access level (`read` or `write`, defaulting to `read`) and comma-separated
+    /// path globs (defaulting to every path).
+    ///
+    /// Each glob is treated as a prefix, so `/releases` also grants access to everything beneath it.
+    fn parse(access: Option<&str>, paths: Option<&str>) -> Result<Self> {
+        let access = match access.unwrap_or("read") {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            other => bail!("Unknown access level {other:?}, expected 'read' or 'write'"),
+        };
+        let patterns = paths
+            .unwrap_or("/")
+            .split(',')
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        Self::new(access, &patterns)
+    }
+
+    /// Create a scope granting access to the given path globs and everything beneath them.
+    fn new(access: Access, patterns: &[&str]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            if !pattern.starts_with('/') {
+                bail!("Path globs must start with '/', got {pattern:?}");
+            }
+            let pattern = pattern.trim_end_matches('/');
+            if !pattern.is_empty() {
+                builder.add(path_glob(pattern)?);
+            }
+            builder.add(path_glob(&format!("{pattern}/**"))?);
+        }
+        Ok(Self {
+            access,
+            paths: builder.build()?,
+        })
+    }
+
+    fn allows(&self, access: Access, path: &str) -> bool {
+        self.access >= access && self.paths.is_match(path)
+    }
+}
+
+fn path_glob(pattern: &str) -> Result<Glob> {
+    Ok(GlobBuilder::new(pattern).literal_separator(true).build()?)
+}
+
+/// The credentials currently loaded from the configured sources.
+#[derive(Default)]
+struct Credentials {
+    tokens: Vec<tokens::Token>,
+    users: HashMap<Box<str>, htpasswd::User>,
+    jwks: Vec<jwt::Key>,
+    clients: HashMap<Box<str>, Scope>,
+}
+
+/// Where to load the keys JWTs are verified with from, and the claims they must carry.
+pub struct JwtConfig {
+    pub jwks_file: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+/// Authenticates requests against signed grants, bearer tokens, JWTs, htpasswd users and TLS client
+/// certificates, reloading credential files when they change.
+pub struct Authenticator {
+    tokens_file: Option<PathBuf>,
+    htpasswd_file: Option<PathBuf>,
+    jwt: Option<JwtConfig>,
+    clients_file: Option<PathBuf>,
+    write_token: Option<Box<str>>,
+    signing_keys: Box<[SigningKey]>,
+    anonymous_access: Option<Access>,
+    credentials: RwLock<Arc<Credentials>>,
+}
+
+/// The identity of an authenticated client, such as a JWT subject, username or client certificate
+/// subject.
+#[derive(Debug, Clone)]
+pub struct Subject(pub Arc<str>);
+
+enum AuthError {
+    /// No valid credentials were presented.
+    Unauthenticated,
+    /// Valid credentials were presented but their scope doesn't cover the request.
+    Forbidden,
+    /// A signed grant was presented that is invalid, expired or doesn't cover the request.
+    InvalidSignature,
+}
+
+impl Authenticator {
+    /// Load the configured credential sources, returning `None` if authentication isn't enabled.
+    pub async fn new(
+        tokens_file: Option<PathBuf>,
+        htpasswd_file: Option<PathBuf>,
+        jwt: Option<JwtConfig>,
+        clients_file: Option<PathBuf>,
+        write_token: Option<String>,
+        signing_keys: Vec<SigningKey>,
+        anonymous_access: Option<AnonymousAccess>,
+    ) -> Result<Option<Arc<Self>>> {
+        let reads_protected = tokens_file.is_some()
+            || htpasswd_file.is_some()
+            || jwt.is_some()
+            || clients_file.is_some()
+            || !signing_keys.is_empty();
+        if !reads_protected && write_token.is_none() {
+            return Ok(None);
+        }
+        // A write token on its own only protects writes, unless reads are explicitly protected too.
+        let anonymous_access = match anonymous_access {
+            Some(AnonymousAccess::Read) => Some(Access::Read),
+            Some(AnonymousAccess::None) => None,
+            None => (!reads_protected).then_some(Access::Read),
+        };
+        let authenticator = Self {
+            tokens_file,
+            htpasswd_file,
+            jwt,
+            clients_file,
+            write_token: write_token.map(Into::into),
+            signing_keys: signing_keys.into(),
+            anonymous_access,
+            credentials: RwLock::default(),
+        };
+        *authenticator.credentials.write().unwrap() = Arc::new(authenticator.load().await?);
+        Ok(Some(Arc::new(authenticator)))
+    }
+
+    async fn load(&self) -> Result<Credentials> {
+        let mut credentials = Credentials::default();
+        if let Some(secret) = &self.write_token {
+            credentials.tokens.push(tokens::Token {
+                secret: secret.clone(),
+                scope: Scope::parse(Some("write"), None)?,
+            });
+        }
+        if let Some(path) = &self.tokens_file {
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read tokens file {path:?}"))?;
+            credentials.tokens.extend(
+                tokens::parse(&contents)
+                    .with_context(|| format!("Failed to parse tokens file {path:?}"))?,
+            );
+        }
+        if let Some(path) = &self.htpasswd_file {
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read htpasswd file {path:?}"))?;
+            credentials.users = htpasswd::parse(&contents)
+                .with_context(|| format!("Failed to parse htpasswd file {path:?}"))?;
+        }
+        if let Some(JwtConfig { jwks_file, .. }) = &self.jwt {
+            let contents = tokio::fs::read_to_string(jwks_file)
+                .await
+                .with_context(|| format!("Failed to read JWKS file {jwks_file:?}"))?;
+            credentials.jwks = jwt::parse(&contents)
+                .with_context(|| format!("Failed to parse JWKS file {jwks_file:?}"))?;
+        }
+        if let Some(path) = &self.clients_file {
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read client certificates file {path:?}"))?;
+            // Shares the tokens file format, with certificate subjects in place of tokens.
+            credentials.clients = tokens::parse(&contents)
+                .with_context(|| format!("Failed to parse client certificates file {path:?}"))?
+                .into_iter()
+                .map(|client| (client.secret, client.scope))
+                .collect();
+        }
+        Ok(credentials)
+    }
+
+    /// Periodically reload the credential files in the background whenever they change.
+    ///
+    /// Files are polled rather than watched as secrets are often replaced through symlink swaps
+    /// (e.g. Kubernetes volumes) which file watchers don't reliably report.
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let authenticator = self.clone();
+        tokio::spawn(async move {
+            let mut stamps = authenticator.file_stamps().await;
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let current = authenticator.file_stamps().await;
+                if current == stamps {
+                    continue;
+                }
+                stamps = current;
+                match authenticator.load().await {
+                    Ok(credentials) => {
+                        *authenticator.credentials.write().unwrap() = Arc::new(credentials);
+                        info!("Reloaded authentication credentials");
+                    }
+                    Err(err) => {
+                        warn!("Failed to reload credentials, keeping the previous ones: {err:#}");
+                    }
+                }
+            }
+        });
+    }
+
+    async fn file_stamps(&self) -> [Option<(SystemTime, u64)>; 4] {
+        async fn stamp(path: Option<&Path>) -> Option<(SystemTime, u64)> {
+            let metadata = tokio::fs::metadata(path?).await.ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        }
+        [
+            stamp(self.tokens_file.as_deref()).await,
+            stamp(self.htpasswd_file.as_deref()).await,
+            stamp(self.jwt.as_ref().map(|jwt| jwt.jwks_file.as_path())).await,
+            stamp(self.clients_file.as_deref()).await,
+        ]
+    }
+
+    /// Check the signed grant carried by a request, or by the `query` it was requested with, covers
+    /// every path it touches, if signing keys are configured.
+    fn verify_signature(&self, req: &Request, query: Option<&str>, paths: &[&str]) -> Verification {
+        if self.signing_keys.is_empty() {
+            return Verification::Missing;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        paths
+            .iter()
+            .map(|path| {
+                signed::verify_request(&self.signing_keys, req, query, path, client_ip(req), now)
+            })
+            .reduce(Verification::and)
+            .unwrap_or(Verification::Missing)
+    }
+
+    async fn authorize(
+        &self,
+        headers: &HeaderMap,
+        client_subject: Option<&str>,
+        signature: Verification,
+        access: Access,
+        paths: &[&str],
+    ) -> Result<Option<Subject>, AuthError> {
+        // Clients presenting credentials are still identified when anonymous access is enough, but
+        // are let through anonymously if the credentials don't check out.
+        let result = self
+            .check_credentials(headers, client_subject, signature, access, paths)
+            .await;
+        match result {
+            Err(_) if self.anonymous_access >= Some(access) => Ok(None),
+            result => result,
+        }
+    }
+
+    async fn check_credentials(
+        &self,
+        headers: &HeaderMap,
+        client_subject: Option<&str>,
+        signature: Verification,
+        access: Access,
+        paths: &[&str],
+    ) -> Result<Option<Subject>, AuthError> {
+        // Credentials in the `Authorization` header take precedence over a stale grant (e.g. an
+        // expired cookie), so an invalid signature is only reported when there are none.
+        let unauthenticated = match signature {
+            Verification::Valid => return Ok(None),
+            Verification::Invalid => AuthError::InvalidSignature,
+            Verification::Missing => AuthError::Unauthenticated,
+        };
+
+        let credentials = self.credentials.read().unwrap().clone();
+        let within = |scope: &Scope, subject: Option<&str>| match paths
+            .iter()
+            .all(|path| scope.allows(access, path))
+        {
+            true => Ok(subject.map(|subject| Subject(subject.into()))),
+            false => Err(AuthError::Forbidden),
+        };
+        let Some(authorization) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            // Without other credentials, clients are identified by their TLS certificate's subject.
+            let client = client_subject
+                .and_then(|subject| Some((subject, credentials.clients.get(subject)?)));
+            return match client {
+                Some((subject, scope)) => within(scope, Some(subject)),
+                None => Err(unauthenticated),
+            };
+        };
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            let token = token.trim();
+            if let Some(token) = credentials
+                .tokens
+                .iter()
+                .find(|candidate| bool::from(candidate.secret.as_bytes().ct_eq(token.as_bytes())))
+            {
+                return within(&token.scope, None);
+            }
+            let config = self.jwt.as_ref().ok_or(AuthError::Unauthenticated)?;
+            let identity = jwt::verify(
+                &credentials.jwks,
+                token,
+                config.issuer.as_deref(),
+                config.audience.as_deref(),
+            )
+            .ok_or(AuthError::Unauthenticated)?;
+            within(&identity.scope, identity.subject.as_deref())
+        } else if let Some(encoded) = authorization.strip_prefix("Basic ") {
+            let decoded = BASE64_STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or(AuthError::Unauthenticated)?;
+            let (username, password) = decoded.split_once(':').ok_or(AuthError::Unauthenticated)?;
+            let user = credentials
+                .users
+                .get(username)
+                .ok_or(AuthError::Unauthenticated)?;
+            let (hash, password) = (user.hash.clone(), password.to_string());
+            // Password hashes are deliberately slow to verify, so keep them off of the async runtime.
+            let verified = tokio::task::spawn_blocking(move || htpasswd::verify(&hash, &password))
+                .await
+                .unwrap_or(false);
+            if !verified {
+                return Err(AuthError::Unauthenticated);
+            }
+            within(&user.scope, Some(username))
+        } else {
+            Err(AuthError::Unauthenticated)
+        }
+    }
+
+    /// The `WWW-Authenticate` challenges for the configured credential sources.
+    fn challenges(&self) -> impl Iterator<Item = HeaderValue> {
+        let bearer =
+            (self.tokens_file.is_some() || self.write_token.is_some() || self.jwt.is_some())
+                .then(|| HeaderValue::from_static("Bearer"));
+        let basic = self
+            .htpasswd_file
+            .is_some()
+            .then(|| HeaderValue::from_static("Basic realm=\"hermes\", charset=\"UTF-8\""));
+        bearer.into_iter().chain(basic)
+    }
+}
+
+/// Middleware that rejects requests without credentials that grant access to the requested path.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> AxumResponse {
+    let Some(authenticator) = &state.auth else {
+        return next.run(req).await;
+    };
+    let access = Access::required_for(req.method());
+    let path = normalize_path(&percent_decode_str(req.uri().path()).decode_utf8_lossy());
+    // Moves also write to their destination, which must be within the same scope.
+    let destination = move_destination(&req);
+    let paths = iter::once(path.as_str())
+        .chain(destination.as_deref())
+        .collect::<Vec<_>>();
+    // Links are signed for the URI the client requested, which rules may have since rewritten.
+    let requested = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri(), |OriginalUri(uri)| uri);
+    let requested_path = normalize_path(&percent_decode_str(requested.path()).decode_utf8_lossy());
+    let signed_paths = iter::once(requested_path.as_str())
+        .chain(destination.as_deref())
+        .collect::<Vec<_>>();
+    let signature = authenticator.verify_signature(&req, requested.query(), &signed_paths);
+    let client_subject = connection(&req).and_then(|connection| connection.client_subject.clone());
+    match authenticator
+        .authorize(
+            req.headers(),
+            client_subject.as_deref(),
+            signature,
+            access,
+            &paths,
+        )
+        .await
+    {
+        Ok(subject) => {
+            let Some(subject) = subject else {
+                return next.run(req).await;
+            };
+            Span::current().record("subject", &*subject.0);
+            req.extensions_mut().insert(subject.clone());
+            // Also exposed to outer layers, such as the access log, through the response.
+            let mut response = next.run(req).await;
+            response.extensions_mut().insert(subject);
+            response
+        }
+        Err(AuthError::Unauthenticated) => {
+            debug!("Rejected unauthenticated {access:?} request for {paths:?}");
+            let mut response = problem_response(StatusCode::UNAUTHORIZED, None, req.headers());
+            for challenge in authenticator.challenges() {
+                response
+                    .headers_mut()
+                    .append(header::WWW_AUTHENTICATE, challenge);
+            }
+            response.into_response()
+        }
+        Err(AuthError::Forbidden) => {
+            debug!("Rejected out of scope {access:?} request for {paths:?}");
+            problem_response(StatusCode::FORBIDDEN, None, req.headers()).into_response()
+        }
+        Err(AuthError::InvalidSignature) => {
+            debug!("Rejected {access:?} request for {paths:?} with an invalid signature");
+            problem_response(
+                StatusCode::FORBIDDEN,
+                Some("The signature is invalid, expired or does not cover this request".into()),
+                req.headers(),
+            )
+            .into_response()
+        }
+    }
+}
+
+fn connection(req: &Request) -> Option<&Connection> {
+    req.extensions()
+        .get::<ConnectInfo<Connection>>()
+        .map(|ConnectInfo(connection)| connection)
+}
+
+/// The normalized path a `MOVE` request's `Destination` header names, if it is one.
+pub fn move_destination(req: &Request) -> Option<String> {
+    if req.method().as_str() != "MOVE" {
+        return None;
+    }
+    let path = destination_path(req.headers()).ok()?;
+    Some(normalize_path(&path))
+}
+
+/// The address of the client that made a request, as resolved by the IP filter.
+fn client_ip(req: &Request) -> Option<IpAddr> {
+    req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip)
+}
+
+/// Whether a normalized path is `prefix` or beneath it.
+pub fn is_within(path: &str, prefix: &str) -> bool {
+    prefix == "/"
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Drop the empty and `.` segments of a decoded path, the same as storage keys do, so scopes are
+/// matched against the path that will actually be served.
+pub fn normalize_path(path: &str) -> String {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<_>>();
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    const SECRET: &str = "a-shared-secret-for-tests";
+
+    /// An authenticator verifying JWTs signed with `SECRET`.
+    fn jwt_authenticator() -> Authenticator {
+        let jwks = serde_json::json!({
+            "keys": [{"kty": "oct", "alg": "HS256", "k": BASE64_URL_SAFE_NO_PAD.encode(SECRET)}],
+        });
+        Authenticator {
+            tokens_file: None,
+            htpasswd_file: None,
+            jwt: Some(JwtConfig {
+                jwks_file: PathBuf::new(),
+                issuer: None,
+                audience: None,
+            }),
+            clients_file: None,
+            write_token: None,
+            signing_keys: Box::default(),
+            anonymous_access: None,
+            credentials: RwLock::new(Arc::new(Credentials {
+                jwks: jwt::parse(&jwks.to_string()).unwrap(),
+                ..Credentials::default()
+            })),
+        }
+    }
+
+    /// Headers carrying a JWT that may write to `paths`.
+    fn bearer(paths: &[&str]) -> HeaderMap {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let claims = serde_json::json!({"sub": "ci", "paths": paths, "ops": ["write"], "exp": exp});
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn jwt_scope_covers_move_within_it() {
+        let result = jwt_authenticator()
+            .authorize(
+                &bearer(&["/uploads"]),
+                None,
+                Verification::Missing,
+                Access::Write,
+                &["/uploads/a.txt", "/uploads/b.txt"],
+            )
+            .await;
+        assert!(matches!(result, Ok(Some(Subject(subject))) if &*subject == "ci"));
+    }
+
+    #[tokio::test]
+    async fn jwt_scope_rejects_move_destination_outside_it() {
+        let result = jwt_authenticator()
+            .authorize(
+                &bearer(&["/uploads"]),
+                None,
+                Verification::Missing,
+                Access::Write,
+                &["/uploads/a.txt", "/index.html"],
+            )
+            .await;
+        assert!(matches!(result, Err(AuthError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn write_token_alone_keeps_reads_anonymous() {
+        let anonymous_access = |signing_keys: Vec<SigningKey>, access| async move {
+            let token = Some("secret".to_owned());
+            let auth = Authenticator::new(None, None, None, None, token, signing_keys, access);
+            auth.await.unwrap().unwrap().anonymous_access
+        };
+        assert_eq!(anonymous_access(Vec::new(), None).await, Some(Access::Read));
+        let none = Some(AnonymousAccess::None);
+        assert_eq!(anonymous_access(Vec::new(), none).await, None);
+        let key = "k1:0123456789abcdef0123456789abcdef".parse().unwrap();
+        assert_eq!(anonymous_access(vec![key], None).await, None);
+    }
+}
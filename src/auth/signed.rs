@@ -0,0 +1,363 @@
+use axum::{
+    extract::Request,
+    http::{Method, header},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use core::{fmt, str::FromStr};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{collections::HashMap, net::IpAddr};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The name of the cookie that carries a signed grant.
+pub const COOKIE_NAME: &str = "hermes_signature";
+
+/// A key used to sign and verify grants, identified by an ID that is carried alongside each
+/// signature so keys can be rotated without invalidating links signed by older ones.
+#[derive(Clone)]
+pub struct SigningKey {
+    id: Box<str>,
+    secret: Box<[u8]>,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, secret) = s
+            .trim()
+            .split_once(':')
+            .ok_or("Signing keys must be in the form '<key id>:<secret>'")?;
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+        {
+            return Err(format!(
+                "Signing key IDs must be non-empty and only contain 'a-z', 'A-Z', '0-9', '-' and '_', got {id:?}"
+            ));
+        }
+        if secret.len() < 16 {
+            return Err(format!(
+                "The secret of signing key {id:?} must be at least 16 characters long"
+            ));
+        }
+        Ok(Self {
+            id: id.into(),
+            secret: secret.as_bytes().into(),
+        })
+    }
+}
+
+/// Temporary access to a path, or every path beneath it, that can be verified without any
+/// server-side state.
+pub struct Grant {
+    /// The normalized path the grant applies to.
+    pub path: String,
+    /// Whether the grant also covers every path beneath `path`.
+    pub prefix: bool,
+    /// The UNIX timestamp (in seconds) after which the grant is no longer valid.
+    pub expires: u64,
+    /// The only client address the grant may be used from, if any.
+    pub ip: Option<IpAddr>,
+    /// The only method the grant allows, or GET and HEAD if unset.
+    pub method: Option<Method>,
+}
+
+impl Grant {
+    fn message(&self, key_id: &str) -> String {
+        format!(
+            "hermes-v1\n{key_id}\n{}\n{}\n{}\n{}\n{}",
+            self.path,
+            self.prefix,
+            self.expires,
+            self.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            self.method.as_ref().map(Method::as_str).unwrap_or_default(),
+        )
+    }
+
+    /// Sign the grant, returning the URL-encoded parameters that carry it in a query or cookie.
+    pub fn sign(&self, key: &SigningKey) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(&key.secret).expect("HMAC accepts keys of any size");
+        mac.update(self.message(&key.id).as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        let mut params = form_urlencoded::Serializer::new(String::new());
+        if self.prefix {
+            params.append_pair("prefix", &self.path);
+        }
+        params.append_pair("expires", &self.expires.to_string());
+        if let Some(ip) = self.ip {
+            params.append_pair("ip", &ip.to_string());
+        }
+        if let Some(method) = &self.method {
+            params.append_pair("method", method.as_str());
+        }
+        params.append_pair("kid", &key.id);
+        params.append_pair("signature", &signature);
+        params.finish()
+    }
+
+    fn allows(&self, method: &Method, path: &str, ip: Option<IpAddr>, now: u64) -> bool {
+        let path_allowed = match self.prefix {
+            true => super::is_within(path, &self.path),
+            false => path == self.path,
+        };
+        let method_allowed = match &self.method {
+            Some(allowed) => {
+                method == allowed || (allowed == Method::GET && method == Method::HEAD)
+            }
+            None => method == Method::GET || method == Method::HEAD,
+        };
+        now <= self.expires
+            && path_allowed
+            && method_allowed
+            && self.ip.is_none_or(|allowed| Some(allowed) == ip)
+    }
+}
+
+/// The outcome of checking a request for a signed grant.
+pub enum Verification {
+    /// The request doesn't carry a signature.
+    Missing,
+    /// The request carries a valid signature that grants it access.
+    Valid,
+    /// The request carries a signature that is invalid, expired or doesn't cover it.
+    Invalid,
+}
+
+impl Verification {
+    /// Combine the verifications of each path a request touches, which a grant must cover all of.
+    pub fn and(self, other: Self) -> Self {
+        match (self, other) {
+            (Verification::Valid, Verification::Valid) => Verification::Valid,
+            (Verification::Missing, _) | (_, Verification::Missing) => Verification::Missing,
+            _ => Verification::Invalid,
+        }
+    }
+}
+
+/// Check the grant carried by a request's `query` parameters, or failing that its signed cookie.
+pub fn verify_request(
+    keys: &[SigningKey],
+    req: &Request,
+    query: Option<&str>,
+    path: &str,
+    ip: Option<IpAddr>,
+    now: u64,
+) -> Verification {
+    let params = query
+        .filter(|query| {
+            form_urlencoded::parse(query.as_bytes()).any(|(name, _)| name == "signature")
+        })
+        .or_else(|| signed_cookie(req));
+    let Some(params) = params else {
+        return Verification::Missing;
+    };
+    match parse_grant(keys, params, path) {
+        Some(grant) if grant.allows(req.method(), path, ip, now) => Verification::Valid,
+        _ => Verification::Invalid,
+    }
+}
+
+fn signed_cookie(req: &Request) -> Option<&str> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(COOKIE_NAME)?.strip_prefix('='))
+}
+
+/// Parse a grant from its parameters and check its signature. Grants without a prefix apply to the
+/// exact path being requested, so their signature only verifies if that is the path they were signed for.
+fn parse_grant(keys: &[SigningKey], params: &str, path: &str) -> Option<Grant> {
+    let params = form_urlencoded::parse(params.as_bytes()).collect::<HashMap<_, _>>();
+    let key = keys
+        .iter()
+        .find(|key| params.get("kid").is_some_and(|id| *key.id == **id))?;
+    let grant = Grant {
+        path: match params.get("prefix") {
+            Some(prefix) => prefix.to_string(),
+            None => path.to_string(),
+        },
+        prefix: params.contains_key("prefix"),
+        expires: params.get("expires")?.parse().ok()?,
+        ip: match params.get("ip") {
+            Some(ip) => Some(ip.parse().ok()?),
+            None => None,
+        },
+        method: match params.get("method") {
+            Some(method) => Some(method.parse().ok()?),
+            None => None,
+        },
+    };
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(params.get("signature")?.as_bytes())
+        .ok()?;
+    let mut mac = HmacSha256::new_from_slice(&key.secret).ok()?;
+    mac.update(grant.message(&key.id).as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    Some(grant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key(id: &str) -> SigningKey {
+        format!("{id}:a-secret-of-sixteen-characters")
+            .parse()
+            .unwrap()
+    }
+
+    fn grant(path: &str) -> Grant {
+        Grant {
+            path: path.to_owned(),
+            prefix: false,
+            expires: NOW + 60,
+            ip: None,
+            method: None,
+        }
+    }
+
+    /// Verify a request carrying `params` in its query against `keys`.
+    fn verify(keys: &[SigningKey], method: Method, path: &str, params: &str) -> Verification {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{path}?{params}"))
+            .body(Body::empty())
+            .unwrap();
+        verify_request(keys, &req, req.uri().query(), path, None, NOW)
+    }
+
+    fn is_valid(verification: Verification) -> bool {
+        matches!(verification, Verification::Valid)
+    }
+
+    #[test]
+    fn links_verify_for_their_path() {
+        let keys = [key("k1")];
+        let params = grant("/report.pdf").sign(&keys[0]);
+        assert!(is_valid(verify(&keys, Method::GET, "/report.pdf", &params)));
+        assert!(is_valid(verify(
+            &keys,
+            Method::HEAD,
+            "/report.pdf",
+            &params
+        )));
+        assert!(!is_valid(verify(&keys, Method::GET, "/other.pdf", &params)));
+        assert!(!is_valid(verify(
+            &keys,
+            Method::PUT,
+            "/report.pdf",
+            &params
+        )));
+    }
+
+    #[test]
+    fn keys_rotate() {
+        let (old, new) = (key("old"), key("new"));
+        let params = grant("/a").sign(&old);
+        assert!(is_valid(verify(
+            &[new.clone(), old],
+            Method::GET,
+            "/a",
+            &params
+        )));
+        assert!(!is_valid(verify(&[new], Method::GET, "/a", &params)));
+    }
+
+    #[test]
+    fn tampered_and_expired_links_are_invalid() {
+        let keys = [key("k1")];
+        let params = grant("/a").sign(&keys[0]);
+        let extended = params.replace(&(NOW + 60).to_string(), &(NOW + 3600).to_string());
+        assert!(matches!(
+            verify(&keys, Method::GET, "/a", &extended),
+            Verification::Invalid
+        ));
+        let expired = Grant {
+            expires: NOW - 1,
+            ..grant("/a")
+        };
+        assert!(matches!(
+            verify(&keys, Method::GET, "/a", &expired.sign(&keys[0])),
+            Verification::Invalid
+        ));
+        assert!(matches!(
+            verify(&keys, Method::GET, "/a", "page=2"),
+            Verification::Missing
+        ));
+    }
+
+    #[test]
+    fn restrictions() {
+        let keys = [key("k1")];
+        let put = Grant {
+            method: Some(Method::PUT),
+            ..grant("/upload")
+        };
+        let params = put.sign(&keys[0]);
+        assert!(is_valid(verify(&keys, Method::PUT, "/upload", &params)));
+        assert!(!is_valid(verify(&keys, Method::GET, "/upload", &params)));
+
+        let ip = "192.0.2.1".parse().unwrap();
+        let bound = Grant {
+            ip: Some(ip),
+            ..grant("/a")
+        }
+        .sign(&keys[0]);
+        let req = Request::get(format!("/a?{bound}"))
+            .body(Body::empty())
+            .unwrap();
+        let query = req.uri().query();
+        assert!(is_valid(verify_request(
+            &keys,
+            &req,
+            query,
+            "/a",
+            Some(ip),
+            NOW
+        )));
+        assert!(!is_valid(verify_request(
+            &keys, &req, query, "/a", None, NOW
+        )));
+    }
+
+    #[test]
+    fn cookies_cover_their_prefix() {
+        let keys = [key("k1")];
+        let params = Grant {
+            prefix: true,
+            ..grant("/private")
+        }
+        .sign(&keys[0]);
+        let verify_cookie = |path: &str| {
+            let req = Request::get(path)
+                .header(
+                    header::COOKIE,
+                    format!("theme=dark; {COOKIE_NAME}={params}"),
+                )
+                .body(Body::empty())
+                .unwrap();
+            verify_request(&keys, &req, None, path, None, NOW)
+        };
+        assert!(is_valid(verify_cookie("/private")));
+        assert!(is_valid(verify_cookie("/private/a/b.txt")));
+        assert!(!is_valid(verify_cookie("/private-other")));
+        assert!(!is_valid(verify_cookie("/public")));
+    }
+}
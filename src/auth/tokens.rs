@@ -0,0 +1,33 @@
+use super::Scope;
+use anyhow::{Context, Result, bail};
+
+/// A static bearer token and the scope it grants.
+pub struct Token {
+    pub secret: Box<str>,
+    pub scope: Scope,
+}
+
+/// Parse a tokens file with one `<token> [read|write] [path globs]` entry per line.
+///
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse(contents: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let secret = fields.next().unwrap_or_default();
+        let scope = Scope::parse(fields.next(), fields.next())
+            .with_context(|| format!("Invalid scope on line {}", number + 1))?;
+        if fields.next().is_some() {
+            bail!("Unexpected trailing fields on line {}", number + 1);
+        }
+        tokens.push(Token {
+            secret: secret.into(),
+            scope,
+        });
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,55 @@
+use axum::http::{HeaderName, HeaderValue};
+use core::{fmt::Display, str::FromStr};
+use duration_human::{DurationHuman, DurationHumanValidator};
+use serde::{Deserialize, Deserializer, de::Error};
+use std::{collections::BTreeMap, time::Duration};
+
+/// A value deserialized from a string through its `FromStr` implementation, so it is written the
+/// same way as the equivalent flag.
+#[derive(Debug, Clone)]
+pub struct Parsed<T>(pub T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map(Self)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// A duration of time such as `1h` or `30min`, from one minute up to 100 years.
+#[derive(Debug, Clone, Copy)]
+pub struct HumanDuration(pub Duration);
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let range =
+            DurationHumanValidator::try_from(("1min", "100years")).map_err(D::Error::custom)?;
+        range
+            .parse_and_validate(&value)
+            .map(|duration: DurationHuman| Self(Duration::from(&duration)))
+            .map_err(|err| D::Error::custom(format!("invalid duration {value:?}: {err}")))
+    }
+}
+
+/// Deserialize a table of header names to values.
+pub fn headers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(HeaderName, HeaderValue)>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| {
+            let value = HeaderValue::try_from(value)
+                .map_err(|_| D::Error::custom(format!("invalid value for header {name:?}")))?;
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|_| D::Error::custom(format!("invalid header name {name:?}")))?;
+            Ok((name, value))
+        })
+        .collect()
+}
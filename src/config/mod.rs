@@ -0,0 +1,261 @@
+mod de;
+mod settings;
+
+use crate::{
+    auth::{AnonymousAccess, SigningKey},
+    hosts::HostPattern,
+    mounts,
+    rules::Rule,
+    storage::StorageBackend,
+    tenants::StorageTemplate,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use axum::http::{HeaderName, HeaderValue};
+use core::fmt::Display;
+pub use de::{HumanDuration, Parsed};
+use globset::Glob;
+use serde::{Deserialize, Deserializer};
+pub use settings::{STORAGE_FLAG, SiteSettings};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// A configuration file declaring named storage backends and the sites served from them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Storage backend URLs by name, e.g. `assets = "fs:///srv/assets"`, or templates opened for
+    /// each tenant such as `sites = "s3://sites/{tenant}/"`.
+    #[serde(default)]
+    pub storage: BTreeMap<String, String>,
+    /// Sites by name.
+    pub sites: BTreeMap<String, SiteConfig>,
+}
+
+/// The settings of a single site. Settings that are left out fall back to the equivalent flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteConfig {
+    /// The name of the storage backend to serve files from.
+    pub storage: Option<String>,
+    pub address: Option<SocketAddr>,
+    /// The hosts the site is served for, e.g. `docs.example.com` or `*.example.com`. Sites sharing
+    /// an address are chosen between by the request's `Host` header.
+    #[serde(default)]
+    pub hosts: Vec<Parsed<HostPattern>>,
+    /// Serve requests for hosts no other site on the address serves. Implied for sites without
+    /// `hosts`.
+    pub default: Option<bool>,
+    pub file_cache_duration: Option<HumanDuration>,
+    pub file_stream_buffersize: Option<usize>,
+    pub index_files: Option<Vec<String>>,
+    pub redirect_directories: Option<bool>,
+    pub autoindex: Option<bool>,
+    pub autoindex_hide: Option<Vec<Parsed<Glob>>>,
+    pub autoindex_page_size: Option<usize>,
+    /// Headers added to every response, replacing any the response already has.
+    #[serde(default, deserialize_with = "de::headers")]
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Storage backends served under path prefixes by prefix, e.g. `/releases`, with `storage`
+    /// serving every other path.
+    #[serde(default)]
+    pub mounts: BTreeMap<String, MountConfig>,
+    /// Rules rewriting or redirecting requests before they are served, of which the first
+    /// matching a request is applied.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+}
+
+/// A storage backend served under a path prefix of a site. Settings that are left out fall back to
+/// the site's.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    /// The name of the storage backend to serve files from.
+    pub storage: String,
+    pub file_cache_duration: Option<HumanDuration>,
+    pub file_stream_buffersize: Option<usize>,
+}
+
+/// The credentials a site authenticates requests with, see the equivalent `--auth-*` flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub tokens_file: Option<PathBuf>,
+    pub htpasswd_file: Option<PathBuf>,
+    pub jwks_file: Option<PathBuf>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub client_certs_file: Option<PathBuf>,
+    pub write_token: Option<String>,
+    pub signing_keys: Option<Vec<Parsed<SigningKey>>>,
+    pub anonymous_access: Option<AnonymousAccess>,
+}
+
+impl Config {
+    /// The site declared with a name.
+    pub fn site(&self, name: &str) -> Result<&SiteConfig> {
+        self.sites.get(name).with_context(|| {
+            format!(
+                "No site named {name:?} is declared, expected one of: {}",
+                self.sites.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+
+    /// Read a TOML or YAML configuration file, depending on its extension.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file {path:?}"))?;
+        let config: Self = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => {
+                let deserializer = toml::Deserializer::parse(&contents)
+                    .with_context(|| format!("Failed to parse configuration file {path:?}"))?;
+                deserialize(deserializer)
+            }
+            Some("yaml" | "yml") => deserialize(serde_yaml_ng::Deserializer::from_str(&contents)),
+            _ => bail!("Configuration file {path:?} must have a .toml, .yaml or .yml extension"),
+        }
+        .with_context(|| format!("Invalid configuration file {path:?}"))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid configuration file {path:?}"))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.sites.is_empty() {
+            bail!("sites: at least one site must be declared");
+        }
+        for (name, url) in &self.storage {
+            StorageTemplate::parse(url).map_err(|err| anyhow!("storage.{name}: {err}"))?;
+        }
+        for (name, site) in &self.sites {
+            let Some(storage) = &site.storage else {
+                bail!("sites.{name}.storage: every site must name the storage backend it serves");
+            };
+            self.check_storage(storage)
+                .map_err(|err| anyhow!("sites.{name}.storage: {err}"))?;
+            let mut prefixes = BTreeMap::new();
+            for (prefix, mount) in &site.mounts {
+                let normalized = mounts::parse_prefix(prefix)
+                    .map_err(|err| anyhow!("sites.{name}.mounts.{prefix:?}: {err}"))?;
+                if let Some(other) = prefixes.insert(normalized, prefix) {
+                    bail!(
+                        "sites.{name}.mounts.{prefix:?}: the same prefix as {other:?} is already mounted"
+                    );
+                }
+                self.check_storage(&mount.storage)
+                    .map_err(|err| anyhow!("sites.{name}.mounts.{prefix:?}.storage: {err}"))?;
+            }
+            self.check_tenants(site)
+                .map_err(|err| anyhow!("sites.{name}.{err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Check that a site serving storage templates names the tenant in every one of its hosts.
+    fn check_tenants(&self, site: &SiteConfig) -> Result<()> {
+        let templates = site
+            .storage
+            .iter()
+            .chain(site.mounts.values().map(|mount| &mount.storage))
+            .filter_map(|storage| Some((storage, self.template(storage)?)))
+            .collect::<Vec<_>>();
+        let Some((storage, _)) = templates.first() else {
+            return Ok(());
+        };
+        if site.hosts.is_empty() {
+            bail!(
+                "hosts: the site serves tenants from storage {storage:?}, so it needs hosts such as `{{tenant}}.example.com`"
+            );
+        }
+        if site.default == Some(true) {
+            bail!("default: the site serves tenants, so it can't serve unmatched hosts");
+        }
+        for Parsed(pattern) in &site.hosts {
+            let HostPattern::Template { placeholder, .. } = pattern else {
+                bail!(
+                    "hosts: {pattern} doesn't name a tenant for storage {storage:?}, expected a host such as `{{tenant}}.example.com`"
+                );
+            };
+            for (storage, template) in &templates {
+                if let Some(other) = template
+                    .placeholders()
+                    .find(|other| other != &&**placeholder)
+                {
+                    bail!(
+                        "hosts: {pattern} names a {{{placeholder}}} but storage {storage:?} uses {{{other}}}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The template of a storage backend, if its URL has placeholders.
+    fn template(&self, name: &str) -> Option<StorageTemplate> {
+        StorageTemplate::parse(self.storage.get(name)?)
+            .ok()
+            .flatten()
+    }
+
+    /// The templates of every storage backend opened for each tenant.
+    pub fn storage_templates(&self) -> BTreeMap<String, StorageTemplate> {
+        self.storage
+            .keys()
+            .filter_map(|name| Some((name.clone(), self.template(name)?)))
+            .collect()
+    }
+
+    fn check_storage(&self, storage: &str) -> Result<()> {
+        if !self.storage.contains_key(storage) {
+            bail!(
+                "no storage backend named {storage:?} is declared, expected one of: {}",
+                self.storage.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Set up every storage backend that is served or mounted by a site, other than templates.
+    pub fn open_storage(&self) -> Result<BTreeMap<String, StorageBackend>> {
+        self.storage
+            .iter()
+            .filter(|(name, _)| self.template(name).is_none())
+            .filter(|(name, _)| {
+                self.sites.values().any(|site| {
+                    site.storage.as_ref() == Some(*name)
+                        || site.mounts.values().any(|mount| mount.storage == **name)
+                })
+            })
+            .map(|(name, url)| {
+                let storage = url
+                    .parse()
+                    .map_err(|err| anyhow!("storage.{name}: {err}"))?;
+                Ok((name.clone(), storage))
+            })
+            .collect()
+    }
+}
+
+/// Deserialize a configuration file, naming the key of any invalid value in the error.
+fn deserialize<'de, D>(deserializer: D) -> Result<Config>
+where
+    D: Deserializer<'de>,
+    D::Error: Display,
+{
+    serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let path = err.path().to_string();
+        let message = err.into_inner().to_string();
+        // Some formats already name the key themselves.
+        match message.starts_with(&path) {
+            true => anyhow!(message),
+            false => anyhow!("{path}: {message}"),
+        }
+    })
+}
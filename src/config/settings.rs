@@ -0,0 +1,203 @@
+use super::{AuthConfig, Config, HumanDuration, MountConfig, Parsed, SiteConfig};
+use crate::Arguments;
+use clap::{ArgMatches, parser::ValueSource};
+use std::{collections::BTreeMap, time::Duration};
+
+/// The name the storage backend given by `--storage` is declared under.
+pub const STORAGE_FLAG: &str = "--storage";
+
+/// How each site's settings are resolved: flags given on the command line or through the
+/// environment override the configuration file, which falls back to every other flag for the
+/// settings it leaves out.
+pub struct SiteSettings {
+    overrides: SiteConfig,
+    defaults: SiteConfig,
+}
+
+impl SiteSettings {
+    pub fn new(args: &Arguments, matches: &ArgMatches) -> Self {
+        let explicit = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        let overrides = SiteConfig {
+            // Sites listen on their own addresses, which `--address` only provides a default for.
+            address: None,
+            ..SiteConfig::from_flags(args, explicit)
+        };
+        Self {
+            overrides,
+            defaults: SiteConfig::from_flags(args, |_| true),
+        }
+    }
+
+    /// Resolve the settings of a site.
+    pub fn resolve(&self, site: SiteConfig) -> SiteConfig {
+        let mut site = self.overrides.clone().or(site).or(self.defaults.clone());
+        // Mounts fall back to their site's settings, and are overridden the same way.
+        site.mounts = std::mem::take(&mut site.mounts)
+            .into_iter()
+            .map(|(prefix, mount)| {
+                let storage = mount.storage.clone();
+                let mount = self
+                    .overrides
+                    .mount(&storage)
+                    .or(mount)
+                    .or(site.mount(&storage));
+                (prefix, mount)
+            })
+            .collect();
+        site
+    }
+
+    /// Resolve the settings of every site of a configuration file, declaring the storage backend
+    /// given by `--storage` if it overrides theirs.
+    pub fn resolve_config(&self, config: &mut Config, storage: Option<&str>) {
+        if let (Some(url), Some(_)) = (storage, &self.overrides.storage) {
+            config
+                .storage
+                .insert(STORAGE_FLAG.to_owned(), url.to_owned());
+        }
+        for site in config.sites.values_mut() {
+            *site = self.resolve(std::mem::take(site));
+        }
+    }
+}
+
+impl SiteConfig {
+    /// The settings given by flags, leaving out those for which `given` is false.
+    fn from_flags(args: &Arguments, given: impl Fn(&str) -> bool + Copy) -> Self {
+        Self {
+            storage: flag(given, "storage", &args.storage).map(|_| STORAGE_FLAG.to_owned()),
+            address: flag(given, "address", &Some(args.address)),
+            hosts: Vec::new(),
+            default: None,
+            file_cache_duration: flag(given, "file_cache_duration", &args.file_cache_duration)
+                .map(|duration| HumanDuration(Duration::from(&duration))),
+            file_stream_buffersize: flag(
+                given,
+                "file_stream_buffersize",
+                &Some(args.file_stream_buffersize),
+            ),
+            index_files: flag(given, "index_files", &Some(args.index_files.clone())),
+            redirect_directories: flag(
+                given,
+                "redirect_directories",
+                &Some(args.redirect_directories),
+            ),
+            autoindex: flag(given, "autoindex", &Some(args.autoindex)),
+            autoindex_hide: flag(given, "autoindex_hide", &Some(args.autoindex_hide.clone()))
+                .map(|globs| globs.into_iter().map(Parsed).collect()),
+            autoindex_page_size: flag(
+                given,
+                "autoindex_page_size",
+                &Some(args.autoindex_page_size),
+            ),
+            headers: Vec::new(),
+            mounts: BTreeMap::new(),
+            rules: Vec::new(),
+            auth: AuthConfig::from_flags(args, given),
+        }
+    }
+
+    /// Fill in the settings left out of `self` from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            storage: self.storage.or(fallback.storage),
+            address: self.address.or(fallback.address),
+            hosts: match self.hosts.is_empty() {
+                true => fallback.hosts,
+                false => self.hosts,
+            },
+            default: self.default.or(fallback.default),
+            file_cache_duration: self.file_cache_duration.or(fallback.file_cache_duration),
+            file_stream_buffersize: self
+                .file_stream_buffersize
+                .or(fallback.file_stream_buffersize),
+            index_files: self.index_files.or(fallback.index_files),
+            redirect_directories: self.redirect_directories.or(fallback.redirect_directories),
+            autoindex: self.autoindex.or(fallback.autoindex),
+            autoindex_hide: self.autoindex_hide.or(fallback.autoindex_hide),
+            autoindex_page_size: self.autoindex_page_size.or(fallback.autoindex_page_size),
+            headers: match self.headers.is_empty() {
+                true => fallback.headers,
+                false => self.headers,
+            },
+            mounts: match self.mounts.is_empty() {
+                true => fallback.mounts,
+                false => self.mounts,
+            },
+            rules: match self.rules.is_empty() {
+                true => fallback.rules,
+                false => self.rules,
+            },
+            auth: self.auth.or(fallback.auth),
+        }
+    }
+
+    /// The settings a mount of `storage` takes from the site.
+    fn mount(&self, storage: &str) -> MountConfig {
+        MountConfig {
+            storage: storage.to_owned(),
+            file_cache_duration: self.file_cache_duration,
+            file_stream_buffersize: self.file_stream_buffersize,
+        }
+    }
+}
+
+impl MountConfig {
+    /// Fill in the settings left out of `self` from `fallback`.
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            storage: self.storage,
+            file_cache_duration: self.file_cache_duration.or(fallback.file_cache_duration),
+            file_stream_buffersize: self
+                .file_stream_buffersize
+                .or(fallback.file_stream_buffersize),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// The credentials given by flags, leaving out those for which `given` is false.
+    fn from_flags(args: &Arguments, given: impl Fn(&str) -> bool + Copy) -> Self {
+        Self {
+            tokens_file: flag(given, "auth_tokens_file", &args.auth_tokens_file),
+            htpasswd_file: flag(given, "auth_htpasswd_file", &args.auth_htpasswd_file),
+            jwks_file: flag(given, "auth_jwks_file", &args.auth_jwks_file),
+            jwt_issuer: flag(given, "auth_jwt_issuer", &args.auth_jwt_issuer),
+            jwt_audience: flag(given, "auth_jwt_audience", &args.auth_jwt_audience),
+            client_certs_file: flag(
+                given,
+                "auth_client_certs_file",
+                &args.auth_client_certs_file,
+            ),
+            write_token: flag(given, "write_token", &args.write_token),
+            signing_keys: flag(given, "signing_keys", &Some(args.signing_keys.clone()))
+                .map(|keys| keys.into_iter().map(Parsed).collect()),
+            anonymous_access: flag(given, "auth_anonymous_access", &args.auth_anonymous_access),
+        }
+    }
+
+    /// Fill in the credentials left out of `self` from `fallback`.
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            tokens_file: self.tokens_file.or(fallback.tokens_file),
+            htpasswd_file: self.htpasswd_file.or(fallback.htpasswd_file),
+            jwks_file: self.jwks_file.or(fallback.jwks_file),
+            jwt_issuer: self.jwt_issuer.or(fallback.jwt_issuer),
+            jwt_audience: self.jwt_audience.or(fallback.jwt_audience),
+            client_certs_file: self.client_certs_file.or(fallback.client_certs_file),
+            write_token: self.write_token.or(fallback.write_token),
+            signing_keys: self.signing_keys.or(fallback.signing_keys),
+            anonymous_access: self.anonymous_access.or(fallback.anonymous_access),
+        }
+    }
+}
+
+/// The value of the flag with the clap ID `id`, if it was `given`.
+fn flag<T: Clone>(given: impl Fn(&str) -> bool, id: &str, value: &Option<T>) -> Option<T> {
+    value.clone().filter(|_| given(id))
+}
@@ -0,0 +1,52 @@
+use anyhow::{Context, Result, bail};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// How long the server has to respond to a health check.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request `path` from the server listening on `address` over plain HTTP, failing unless it
+/// responds with `200 OK`.
+pub async fn check(address: SocketAddr, path: &str) -> Result<()> {
+    // A server listening on every interface can be reached through loopback.
+    let address = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port())
+        }
+        _ => address,
+    };
+    let status_line = tokio::time::timeout(TIMEOUT, request(address, path))
+        .await
+        .with_context(|| format!("Timed out checking http://{address}{path}"))?
+        .with_context(|| format!("Failed to check http://{address}{path}"))?;
+    match status_line.split(' ').nth(1) {
+        Some("200") => Ok(()),
+        _ => bail!(
+            "http://{address}{path} responded with {:?}",
+            status_line.trim_end()
+        ),
+    }
+}
+
+/// Make a `GET` request, returning the status line of the response.
+async fn request(address: SocketAddr, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream
+        .write_all(
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await?;
+    Ok(status_line)
+}
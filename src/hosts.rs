@@ -0,0 +1,488 @@
+use crate::{
+    routes::problem_response,
+    tenants::{Tenants, is_placeholder_name, is_tenant_name},
+};
+use anyhow::{Result, bail};
+use axum::{
+    Router,
+    extract::Request,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use core::{
+    cmp::Reverse,
+    convert::Infallible,
+    fmt,
+    str::FromStr,
+    task::{Context, Poll},
+};
+use futures_util::future::BoxFuture;
+use std::{collections::HashMap, sync::Arc};
+use tower::Service;
+use tracing::debug;
+
+/// A host a site is served for, either exactly (`docs.example.com`), any subdomain of a domain
+/// (`*.example.com`) or a tenant's subdomain (`{tenant}.example.com`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HostPattern {
+    Exact(Box<str>),
+    /// The suffix subdomains must end with, including the leading dot.
+    Wildcard(Box<str>),
+    /// A single label naming a tenant, followed by a suffix including the leading dot.
+    Template {
+        placeholder: Box<str>,
+        suffix: Box<str>,
+    },
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let host = normalize_host(s);
+        let template = host
+            .strip_prefix('{')
+            .and_then(|host| host.split_once("}."))
+            .filter(|(placeholder, _)| is_placeholder_name(placeholder));
+        let (pattern, domain) = match (host.strip_prefix("*."), template) {
+            (Some(domain), _) => (Self::Wildcard(format!(".{domain}").into()), domain),
+            (None, Some((placeholder, domain))) => (
+                Self::Template {
+                    placeholder: placeholder.into(),
+                    suffix: format!(".{domain}").into(),
+                },
+                domain,
+            ),
+            (None, None) => (Self::Exact(host.as_str().into()), host.as_str()),
+        };
+        if domain.is_empty()
+            || domain.split('.').any(|label| {
+                label.is_empty()
+                    || !label
+                        .chars()
+                        .all(|char| char.is_ascii_alphanumeric() || char == '-')
+            })
+        {
+            return Err(format!(
+                "invalid host {s:?}, expected a hostname such as `example.com`, `*.example.com` or `{{tenant}}.example.com`"
+            ));
+        }
+        Ok(pattern)
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Exact(host) => f.write_str(host),
+            HostPattern::Wildcard(suffix) => write!(f, "*{suffix}"),
+            HostPattern::Template {
+                placeholder,
+                suffix,
+            } => write!(f, "{{{placeholder}}}{suffix}"),
+        }
+    }
+}
+
+impl HostPattern {
+    /// Whether a normalised host matches the pattern, with templates matching any tenant.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => **exact == *host,
+            HostPattern::Wildcard(suffix) => host.ends_with(&**suffix),
+            HostPattern::Template { suffix, .. } => {
+                host.strip_suffix(&**suffix).is_some_and(is_tenant_name)
+            }
+        }
+    }
+
+    /// How the pattern ranks among others matching the same host, lowest first: exact hosts, then
+    /// tenants and then wildcards, with longer suffixes first.
+    pub fn precedence(&self) -> (u8, Reverse<usize>) {
+        match self {
+            HostPattern::Exact(_) => (0, Reverse(0)),
+            HostPattern::Template { suffix, .. } => (1, Reverse(suffix.len())),
+            HostPattern::Wildcard(suffix) => (2, Reverse(suffix.len())),
+        }
+    }
+}
+
+/// Lowercase a hostname and remove any trailing dot, so equivalent hostnames compare equal.
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The site serving a host, along with its name for logs and errors.
+#[derive(Clone)]
+struct Site {
+    name: Arc<str>,
+    routes: Routes,
+}
+
+/// How a site routes requests, either the same for every host or separately for each tenant.
+#[derive(Clone)]
+enum Routes {
+    Router(Router),
+    Tenants(Arc<Tenants>),
+}
+
+/// The sites served on a listener by the hosts they serve.
+#[derive(Default)]
+pub struct Hosts {
+    exact: HashMap<Box<str>, Site>,
+    /// Tenant suffixes, longest first so the most specific one matches.
+    templates: Vec<(Box<str>, Site)>,
+    /// Wildcard suffixes, longest first so the most specific one matches.
+    wildcards: Vec<(Box<str>, Site)>,
+    default: Option<Site>,
+    /// Serves health checks made for hosts no site serves, such as the listener's address.
+    health: Option<Router>,
+}
+
+impl Hosts {
+    /// Serve the hosts matching `pattern` from a site.
+    pub fn add(&mut self, pattern: &HostPattern, name: &str, router: &Router) -> Result<()> {
+        self.insert(pattern, name, Routes::Router(router.clone()))
+    }
+
+    /// Serve the tenants of the hosts matching `pattern`, which must be a template.
+    pub fn add_tenants(
+        &mut self,
+        pattern: &HostPattern,
+        name: &str,
+        tenants: &Arc<Tenants>,
+    ) -> Result<()> {
+        if !matches!(pattern, HostPattern::Template { .. }) {
+            bail!(
+                "{pattern} doesn't name a tenant, expected a host such as `{{tenant}}.example.com`"
+            );
+        }
+        self.insert(pattern, name, Routes::Tenants(tenants.clone()))
+    }
+
+    fn insert(&mut self, pattern: &HostPattern, name: &str, routes: Routes) -> Result<()> {
+        let site = Site {
+            name: name.into(),
+            routes,
+        };
+        let existing = match pattern {
+            HostPattern::Exact(host) => self.exact.insert(host.clone(), site),
+            HostPattern::Wildcard(suffix) => insert_suffix(&mut self.wildcards, suffix, site),
+            HostPattern::Template { suffix, .. } => {
+                insert_suffix(&mut self.templates, suffix, site)
+            }
+        };
+        if let Some(existing) = existing {
+            bail!("{pattern} is already served by site {:?}", existing.name);
+        }
+        Ok(())
+    }
+
+    /// Serve requests for hosts no other site serves from a site.
+    pub fn set_default(&mut self, name: &str, router: &Router) -> Result<()> {
+        if let Some(existing) = &self.default {
+            bail!("site {:?} already serves unmatched hosts", existing.name);
+        }
+        self.default = Some(Site {
+            name: name.into(),
+            routes: Routes::Router(router.clone()),
+        });
+        Ok(())
+    }
+
+    /// Serve health checks for hosts no site serves from a router, so orchestrators can check the
+    /// listener by its address.
+    pub fn set_health(&mut self, router: Router) {
+        self.health = Some(router);
+    }
+
+    /// Route requests between the sites.
+    pub fn into_router(self) -> Router {
+        Router::new().fallback_service(HostRouter(Arc::new(self)))
+    }
+
+    /// The router serving a host, trying exact hosts, then tenants, then wildcards and finally the
+    /// default site. Tenants without files are skipped like hosts that aren't served.
+    async fn router(&self, host: Option<&str>) -> Option<(&Site, Router)> {
+        if let Some(host) = host {
+            if let Some(site) = self.exact.get(host) {
+                return Some((site, site.routes.router()?));
+            }
+            for (suffix, site) in &self.templates {
+                let Some(tenant) = host
+                    .strip_suffix(&**suffix)
+                    .filter(|tenant| is_tenant_name(tenant))
+                else {
+                    continue;
+                };
+                let router = match &site.routes {
+                    Routes::Router(router) => Some(router.clone()),
+                    Routes::Tenants(tenants) => tenants.router(tenant).await,
+                };
+                if let Some(router) = router {
+                    return Some((site, router));
+                }
+            }
+            if let Some((_, site)) = self
+                .wildcards
+                .iter()
+                .find(|(suffix, _)| host.ends_with(&**suffix))
+            {
+                return Some((site, site.routes.router()?));
+            }
+        }
+        let site = self.default.as_ref()?;
+        Some((site, site.routes.router()?))
+    }
+}
+
+impl Routes {
+    /// The router serving every host of the site, if it isn't routed by tenant.
+    fn router(&self) -> Option<Router> {
+        match self {
+            Routes::Router(router) => Some(router.clone()),
+            Routes::Tenants(_) => None,
+        }
+    }
+}
+
+/// Add a site for a suffix to a list kept longest first, returning any site it replaced.
+fn insert_suffix(sites: &mut Vec<(Box<str>, Site)>, suffix: &str, site: Site) -> Option<Site> {
+    match sites.iter().position(|(other, _)| **other == *suffix) {
+        Some(index) => Some(std::mem::replace(&mut sites[index].1, site)),
+        None => {
+            sites.push((suffix.into(), site));
+            sites.sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+            None
+        }
+    }
+}
+
+/// The normalised host a request was made to, without its port.
+pub fn request_host(req: &Request) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(header::HOST)?.to_str().ok()?,
+    };
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next(),
+        None => host.split(':').next(),
+    };
+    host.map(normalize_host)
+}
+
+/// Routes each request to the site serving the host it was made to.
+#[derive(Clone)]
+struct HostRouter(Arc<Hosts>);
+
+impl Service<Request> for HostRouter {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let hosts = self.0.clone();
+        Box::pin(async move {
+            let host = request_host(&req);
+            match hosts.router(host.as_deref()).await {
+                Some((site, mut router)) => {
+                    debug!("Routing request for {host:?} to site {:?}", site.name);
+                    router.call(req).await
+                }
+                None => match &hosts.health {
+                    Some(router) if matches!(req.uri().path(), "/healthz" | "/readyz") => {
+                        router.clone().call(req).await
+                    }
+                    _ => {
+                        debug!("No site serves {host:?}");
+                        Ok(misdirected(req.headers()))
+                    }
+                },
+            }
+        })
+    }
+}
+
+/// Answer requests for hosts that aren't served on the address they were made to.
+pub fn misdirected(headers: &HeaderMap) -> Response {
+    problem_response(
+        StatusCode::MISDIRECTED_REQUEST,
+        Some("No site is served for this host".to_owned()),
+        headers,
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get};
+    use tower::ServiceExt;
+
+    fn pattern(host: &str) -> HostPattern {
+        host.parse().unwrap()
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(
+            pattern("Docs.Example.com."),
+            HostPattern::Exact("docs.example.com".into())
+        );
+        assert_eq!(
+            pattern("*.example.com"),
+            HostPattern::Wildcard(".example.com".into())
+        );
+        assert_eq!(
+            pattern("{tenant}.example.com"),
+            HostPattern::Template {
+                placeholder: "tenant".into(),
+                suffix: ".example.com".into(),
+            }
+        );
+        for host in [
+            "",
+            "*.",
+            "exa mple.com",
+            "a..b",
+            "*.*.example.com",
+            "{}.example.com",
+        ] {
+            assert!(host.parse::<HostPattern>().is_err(), "{host:?}");
+        }
+    }
+
+    #[test]
+    fn matching() {
+        assert!(pattern("example.com").matches("example.com"));
+        assert!(!pattern("example.com").matches("www.example.com"));
+        assert!(pattern("*.example.com").matches("a.b.example.com"));
+        assert!(!pattern("*.example.com").matches("example.com"));
+        assert!(!pattern("*.example.com").matches("badexample.com"));
+        assert!(pattern("{tenant}.example.com").matches("acme.example.com"));
+        assert!(!pattern("{tenant}.example.com").matches("a.b.example.com"));
+        let mut patterns = [
+            pattern("*.example.com"),
+            pattern("*.docs.example.com"),
+            pattern("{tenant}.docs.example.com"),
+            pattern("a.docs.example.com"),
+        ];
+        patterns.sort_by_key(HostPattern::precedence);
+        assert_eq!(
+            patterns.map(|pattern| pattern.to_string()),
+            [
+                "a.docs.example.com",
+                "{tenant}.docs.example.com",
+                "*.docs.example.com",
+                "*.example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn request_hosts() {
+        let host = |host: &str| {
+            let req = Request::get("/")
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap();
+            request_host(&req)
+        };
+        assert_eq!(host("Example.com:8080").as_deref(), Some("example.com"));
+        assert_eq!(host("example.com.").as_deref(), Some("example.com"));
+        assert_eq!(host("[::1]:8080").as_deref(), Some("::1"));
+        let req = Request::get("http://absolute.example.com/")
+            .header(header::HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("absolute.example.com"));
+    }
+
+    /// A router answering every request with `name`.
+    fn site(name: &'static str) -> Router {
+        Router::new().fallback(get(move || async move { name }))
+    }
+
+    /// The body of the response to a request for `path` on `host`, or its status if it failed.
+    async fn serve(router: &Router, host: &str, path: &str) -> Result<String, StatusCode> {
+        let req = Request::get(path)
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(req).await.unwrap();
+        if !response.status().is_success() {
+            return Err(response.status());
+        }
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn routing() {
+        let mut hosts = Hosts::default();
+        hosts
+            .add(&pattern("www.example.com"), "www", &site("www"))
+            .unwrap();
+        hosts
+            .add(&pattern("*.example.com"), "any", &site("any"))
+            .unwrap();
+        hosts
+            .add(&pattern("*.docs.example.com"), "docs", &site("docs"))
+            .unwrap();
+        hosts.set_health(site("health"));
+        let router = hosts.into_router();
+
+        assert_eq!(
+            serve(&router, "WWW.example.com:443", "/").await.unwrap(),
+            "www"
+        );
+        assert_eq!(serve(&router, "api.example.com", "/").await.unwrap(), "any");
+        assert_eq!(
+            serve(&router, "v1.docs.example.com", "/").await.unwrap(),
+            "docs"
+        );
+        assert_eq!(
+            serve(&router, "example.org", "/").await,
+            Err(StatusCode::MISDIRECTED_REQUEST)
+        );
+        assert_eq!(
+            serve(&router, "127.0.0.1", "/healthz").await.unwrap(),
+            "health"
+        );
+        assert_eq!(
+            serve(&router, "api.example.com", "/healthz").await.unwrap(),
+            "any"
+        );
+    }
+
+    #[test]
+    fn hosts_are_served_by_one_site() {
+        let mut hosts = Hosts::default();
+        for name in ["first", "second"] {
+            let result = hosts.add(&pattern("*.example.com"), name, &site(name));
+            assert_eq!(result.is_err(), name == "second");
+        }
+    }
+
+    #[tokio::test]
+    async fn default_site() {
+        let mut hosts = Hosts::default();
+        hosts
+            .add(&pattern("example.com"), "main", &site("main"))
+            .unwrap();
+        hosts.set_default("fallback", &site("fallback")).unwrap();
+        assert!(hosts.set_default("again", &site("again")).is_err());
+        let router = hosts.into_router();
+
+        assert_eq!(serve(&router, "example.com", "/").await.unwrap(), "main");
+        assert_eq!(
+            serve(&router, "example.org", "/").await.unwrap(),
+            "fallback"
+        );
+    }
+}
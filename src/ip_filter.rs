@@ -0,0 +1,230 @@
+use crate::{
+    AppState,
+    auth::{is_within, move_destination, normalize_path},
+    listener::Connection,
+    routes::problem_response,
+};
+use anyhow::{Context, Result, bail};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use ipnet::IpNet;
+use percent_encoding::percent_decode_str;
+use std::{
+    iter,
+    net::{IpAddr, SocketAddr},
+};
+use tracing::debug;
+
+/// The address of the client that made a request, after accounting for trusted proxies.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Parse a network in CIDR notation, or a single address.
+pub fn parse_network(value: &str) -> Result<IpNet, String> {
+    let value = value.trim();
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => value
+            .parse::<IpNet>()
+            .map_err(|_| format!("Expected an IP address or CIDR network, got {value:?}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+/// The rules for a single path prefix, in the order they were written.
+struct PrefixRules {
+    prefix: String,
+    rules: Vec<(Action, Vec<IpNet>)>,
+}
+
+/// Allow and deny rules for the client addresses that may access each path prefix.
+#[derive(Default)]
+pub struct IpRules(Vec<PrefixRules>);
+
+impl IpRules {
+    /// Parse a rules file with one `<path prefix> allow|deny <networks>` rule per line, where networks
+    /// are comma-separated CIDR networks, addresses or `all`.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut prefixes = Vec::<PrefixRules>::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [prefix, action, networks] = fields.as_slice() else {
+                bail!(
+                    "Expected '<path prefix> allow|deny <networks>' on line {}",
+                    number + 1
+                );
+            };
+            if !prefix.starts_with('/') {
+                bail!(
+                    "Path prefixes must start with '/', got {prefix:?} on line {}",
+                    number + 1
+                );
+            }
+            let action = match *action {
+                "allow" => Action::Allow,
+                "deny" => Action::Deny,
+                other => bail!(
+                    "Unknown action {other:?} on line {}, expected 'allow' or 'deny'",
+                    number + 1
+                ),
+            };
+            let mut parsed = Vec::new();
+            for network in networks.split(',') {
+                match network {
+                    "all" => parsed
+                        .extend(["0.0.0.0/0", "::/0"].map(|all| all.parse::<IpNet>().unwrap())),
+                    network => parsed.push(
+                        parse_network(network)
+                            .map_err(anyhow::Error::msg)
+                            .with_context(|| format!("Invalid network on line {}", number + 1))?,
+                    ),
+                }
+            }
+
+            let prefix = normalize_path(prefix);
+            match prefixes.iter_mut().find(|rules| rules.prefix == prefix) {
+                Some(rules) => rules.rules.push((action, parsed)),
+                None => prefixes.push(PrefixRules {
+                    prefix,
+                    rules: vec![(action, parsed)],
+                }),
+            }
+        }
+        // The most specific prefix takes precedence.
+        prefixes.sort_by_key(|rules| std::cmp::Reverse(rules.prefix.len()));
+        Ok(Self(prefixes))
+    }
+
+    /// Check whether a client may access a path, using the rules of the longest prefix covering it.
+    ///
+    /// The first rule with a network containing the client decides, and if none do the client is only
+    /// allowed when the prefix has no `allow` rules.
+    fn allows(&self, path: &str, ip: IpAddr) -> bool {
+        let Some(prefix) = self.0.iter().find(|rules| is_within(path, &rules.prefix)) else {
+            return true;
+        };
+        let matching = prefix
+            .rules
+            .iter()
+            .find(|(_, networks)| networks.iter().any(|network| network.contains(&ip)));
+        match matching {
+            Some((action, _)) => *action == Action::Allow,
+            None => !prefix
+                .rules
+                .iter()
+                .any(|(action, _)| *action == Action::Allow),
+        }
+    }
+}
+
+/// Resolve the address of the client behind any trusted proxies from the `Forwarded` or
+/// `X-Forwarded-For` headers.
+///
+/// Addresses are walked from the nearest proxy outwards, stopping at the first one that isn't
+/// trusted as anything before it could have been forged by the client.
+fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    if !trusted(&peer) {
+        return peer;
+    }
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED);
+    let hops = match forwarded.is_empty() {
+        false => forwarded
+            .iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .collect::<Vec<_>>(),
+        true => values(HeaderName::from_static("x-forwarded-for"))
+            .into_iter()
+            .map(Some)
+            .collect(),
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(ip) = hop.and_then(parse_forwarded_ip) else {
+            break;
+        };
+        client = ip;
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Parse an address from a forwarding header, which may be quoted and include a port.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            value
+                .trim_start_matches('[')
+                .split_once(']')
+                .map_or(value, |(ip, _)| ip)
+                .parse::<IpAddr>()
+        })
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// Middleware that resolves the client's address and rejects requests from addresses the IP rules
+/// don't allow to access the requested path.
+pub async fn filter(State(state): State<AppState>, mut req: Request, next: Next) -> AxumResponse {
+    let Some(ConnectInfo(connection)) = req.extensions().get::<ConnectInfo<Connection>>() else {
+        return next.run(req).await;
+    };
+    let ip = resolve_client_ip(
+        connection.remote_addr.ip().to_canonical(),
+        req.headers(),
+        &state.trusted_proxies,
+    );
+    req.extensions_mut().insert(ClientIp(ip));
+
+    let path = normalize_path(&percent_decode_str(req.uri().path()).decode_utf8_lossy());
+    // Moves also write to their destination, which the client must be allowed to access too.
+    let destination = move_destination(&req);
+    let allowed = iter::once(&path)
+        .chain(&destination)
+        .all(|path| state.ip_rules.allows(path, ip));
+    let mut response = match allowed {
+        true => next.run(req).await,
+        false => {
+            debug!("Rejected request from {ip} for {path:?} by IP rules");
+            problem_response(StatusCode::FORBIDDEN, None, req.headers()).into_response()
+        }
+    };
+    // Also exposed to outer layers, such as the access log, through the response.
+    response.extensions_mut().insert(ClientIp(ip));
+    response
+}
@@ -0,0 +1,423 @@
+use crate::{
+    AppState,
+    auth::{Subject, is_within, normalize_path},
+    ip_filter::ClientIp,
+    listener::Connection,
+    routes::problem_response,
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use core::str::FromStr;
+use futures_util::StreamExt;
+use percent_encoding::percent_decode_str;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// How often buckets that have refilled completely are dropped, as they are no different from new ones.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A bucket of tokens that refills continuously at a fixed rate up to its capacity.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take a single token, or return how long it will be until one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.check(now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Check that a token is available without taking it, or return how long it will be until
+    /// one is.
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    /// Take `amount` tokens, going into debt if there aren't enough, and return how long to wait
+    /// before using them so that the rate is respected.
+    fn reserve(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate)
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Token buckets that are created on demand for each key.
+struct Buckets<K>(Mutex<HashMap<K, TokenBucket>>);
+
+impl<K: Eq + Hash> Buckets<K> {
+    fn with<T>(
+        &self,
+        key: K,
+        new: impl FnOnce() -> TokenBucket,
+        f: impl FnOnce(&mut TokenBucket) -> T,
+    ) -> T {
+        let mut buckets = self.0.lock().unwrap();
+        f(buckets.entry(key).or_insert_with(new))
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Self(Mutex::default())
+    }
+}
+
+/// A number of requests allowed over a period, e.g. `100/min`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    requests: u32,
+    period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, period) = s
+            .trim()
+            .split_once('/')
+            .ok_or("Rate limits must be in the form '<requests>/<period>', e.g. '100/min'")?;
+        let requests = requests
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|requests| *requests > 0)
+            .ok_or_else(|| format!("Expected a positive number of requests, got {requests:?}"))?;
+        let period = match period.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            "d" | "day" => Duration::from_secs(24 * 60 * 60),
+            other => {
+                return Err(format!(
+                    "Unknown period {other:?}, expected 's', 'min', 'hour' or 'day'"
+                ));
+            }
+        };
+        Ok(Self { requests, period })
+    }
+}
+
+/// Who a request is counted against, its authenticated identity or otherwise its address.
+#[derive(PartialEq, Eq, Hash)]
+enum ClientKey {
+    Subject(Arc<str>),
+    Ip(IpAddr),
+}
+
+/// Limits the rate of requests each client can make.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    clients: Buckets<ClientKey>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `burst` requests at once, defaulting to the number of requests in
+    /// the limit's period.
+    pub fn new(limit: RateLimit, burst: Option<u32>) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            rate: f64::from(limit.requests) / limit.period.as_secs_f64(),
+            burst: f64::from(burst.unwrap_or(limit.requests)),
+            clients: Buckets::default(),
+        });
+        spawn_pruner(Arc::downgrade(&limiter), |limiter| limiter.clients.prune());
+        limiter
+    }
+}
+
+/// A number of bytes per second, e.g. `10MiB`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRate(f64);
+
+impl FromStr for ByteRate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        parse_bytes(s.strip_suffix("/s").unwrap_or(s)).map(Self)
+    }
+}
+
+/// Parse a positive number of bytes with an optional decimal or binary unit, e.g. `500K` or `10MiB`.
+pub fn parse_bytes(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|char: char| !(char.is_ascii_digit() || char == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "kib" => 1024.0,
+        "m" | "mb" => 1e6,
+        "mib" => 1024.0 * 1024.0,
+        "g" | "gb" => 1e9,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        other => {
+            return Err(format!(
+                "Unknown unit {other:?}, expected e.g. 'KiB', 'MB' or 'GiB'"
+            ));
+        }
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|number| *number > 0.0)
+        .map(|number| number * multiplier)
+        .ok_or_else(|| format!("Expected a positive number of bytes, got {number:?}"))
+}
+
+/// A byte rate that applies to responses for paths beneath a prefix, e.g. `/releases=10MiB`.
+#[derive(Debug, Clone)]
+pub struct PrefixRate {
+    prefix: String,
+    rate: ByteRate,
+}
+
+impl FromStr for PrefixRate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, rate) = s.trim().split_once('=').unwrap_or(("/", s));
+        if !prefix.starts_with('/') {
+            return Err(format!("Path prefixes must start with '/', got {prefix:?}"));
+        }
+        Ok(Self {
+            prefix: normalize_path(prefix),
+            rate: rate.parse()?,
+        })
+    }
+}
+
+/// Limits the rate response bodies are sent at, both in total and for each connection, with the
+/// limit for the longest prefix covering a path applying to it.
+pub struct Bandwidth {
+    global: Vec<(String, Mutex<TokenBucket>)>,
+    per_connection: Vec<PrefixRate>,
+    connections: Buckets<(usize, u64)>,
+}
+
+impl Bandwidth {
+    /// Create the bandwidth limits, returning `None` if there are none.
+    pub fn new(
+        mut global: Vec<PrefixRate>,
+        mut per_connection: Vec<PrefixRate>,
+    ) -> Option<Arc<Self>> {
+        if global.is_empty() && per_connection.is_empty() {
+            return None;
+        }
+        for limits in [&mut global, &mut per_connection] {
+            limits.sort_by_key(|limit| std::cmp::Reverse(limit.prefix.len()));
+        }
+        let bandwidth = Arc::new(Self {
+            global: global
+                .into_iter()
+                .map(|limit| {
+                    (
+                        limit.prefix,
+                        Mutex::new(TokenBucket::new(limit.rate.0, limit.rate.0)),
+                    )
+                })
+                .collect(),
+            per_connection,
+            connections: Buckets::default(),
+        });
+        spawn_pruner(Arc::downgrade(&bandwidth), |bandwidth| {
+            bandwidth.connections.prune()
+        });
+        Some(bandwidth)
+    }
+
+    /// Wait until `amount` bytes may be sent under the given global and per-connection limits.
+    async fn acquire(
+        &self,
+        global: Option<usize>,
+        connection: Option<(usize, u64)>,
+        amount: usize,
+    ) {
+        let now = Instant::now();
+        let amount = amount as f64;
+        let global_wait =
+            global.map(|index| self.global[index].1.lock().unwrap().reserve(amount, now));
+        let connection_wait = connection.map(|key| {
+            let rate = self.per_connection[key.0].rate.0;
+            self.connections.with(
+                key,
+                || TokenBucket::new(rate, rate),
+                |bucket| bucket.reserve(amount, now),
+            )
+        });
+        let wait = global_wait.max(connection_wait).unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Periodically prune a limiter's buckets for as long as it is in use.
+fn spawn_pruner<T: Send + Sync + 'static>(limiter: std::sync::Weak<T>, prune: fn(&T)) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let Some(limiter) = limiter.upgrade() else {
+                break;
+            };
+            prune(&limiter);
+        }
+    });
+}
+
+/// Middleware that rejects requests from clients that have exceeded the rate limit with a
+/// `429 Too Many Requests`, telling them when to retry through `Retry-After`.
+pub async fn limit_rate(State(state): State<AppState>, req: Request, next: Next) -> AxumResponse {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(req).await;
+    };
+    let key = match (
+        req.extensions().get::<Subject>(),
+        req.extensions().get::<ClientIp>(),
+    ) {
+        (Some(Subject(subject)), _) => ClientKey::Subject(subject.clone()),
+        (None, Some(ClientIp(ip))) => ClientKey::Ip(*ip),
+        (None, None) => return next.run(req).await,
+    };
+    let allowed = limiter.clients.with(
+        key,
+        || TokenBucket::new(limiter.rate, limiter.burst),
+        |bucket| bucket.try_take(Instant::now()),
+    );
+    match allowed {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => too_many_requests(&req, retry_after),
+    }
+}
+
+/// Middleware, placed before authentication, that counts requests failing authentication against
+/// the client's address and turns the address away before its credentials are checked once it is
+/// out of requests, so credentials can't be guessed faster than the rate limit allows.
+pub async fn limit_failed_attempts(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> AxumResponse {
+    let (Some(limiter), Some(&ClientIp(ip))) =
+        (&state.rate_limiter, req.extensions().get::<ClientIp>())
+    else {
+        return next.run(req).await;
+    };
+    let bucket = || TokenBucket::new(limiter.rate, limiter.burst);
+    let key = || ClientKey::Ip(ip);
+    if let Err(retry_after) = limiter
+        .clients
+        .with(key(), bucket, |bucket| bucket.check(Instant::now()))
+    {
+        return too_many_requests(&req, retry_after);
+    }
+    let response = next.run(req).await;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let _ = limiter
+            .clients
+            .with(key(), bucket, |bucket| bucket.try_take(Instant::now()));
+    }
+    response
+}
+
+/// A `429 Too Many Requests` telling the client when to retry through `Retry-After`.
+fn too_many_requests(req: &Request, retry_after: Duration) -> AxumResponse {
+    debug!("Rejected rate limited request for {}", req.uri().path());
+    let mut response = problem_response(StatusCode::TOO_MANY_REQUESTS, None, req.headers());
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+    );
+    response.into_response()
+}
+
+/// Middleware that throttles response bodies to the configured bandwidth limits.
+pub async fn limit_bandwidth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> AxumResponse {
+    let Some(bandwidth) = state.bandwidth.clone() else {
+        return next.run(req).await;
+    };
+    let path = normalize_path(&percent_decode_str(req.uri().path()).decode_utf8_lossy());
+    let global = bandwidth
+        .global
+        .iter()
+        .position(|(prefix, _)| is_within(&path, prefix));
+    let connection =
+        req.extensions()
+            .get::<ConnectInfo<Connection>>()
+            .and_then(|ConnectInfo(connection)| {
+                let index = bandwidth
+                    .per_connection
+                    .iter()
+                    .position(|limit| is_within(&path, &limit.prefix))?;
+                Some((index, connection.id))
+            });
+    if global.is_none() && connection.is_none() {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = next.run(req).await.into_parts();
+    let body = body.into_data_stream().then(move |chunk| {
+        let bandwidth = bandwidth.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                bandwidth.acquire(global, connection, bytes.len()).await;
+            }
+            chunk
+        }
+    });
+    AxumResponse::from_parts(parts, Body::from_stream(body))
+}
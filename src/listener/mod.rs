@@ -0,0 +1,247 @@
+mod proxy_protocol;
+
+use crate::tls::{self, TlsConfig};
+use anyhow::{Result, bail};
+use axum::{extract::connect_info::Connected, serve::IncomingStream};
+use ipnet::IpNet;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{Semaphore, mpsc},
+};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error};
+
+/// How long a client has to send its PROXY protocol header and complete the TLS handshake before
+/// it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of established connections that may wait to be served.
+const BACKLOG: usize = 1024;
+
+/// The number of handshakes that may be in flight at once, after which connections are left in
+/// the kernel's backlog until a handshake completes.
+const MAX_HANDSHAKES: usize = 1024;
+
+/// Information about an accepted connection, available to handlers through `ConnectInfo`.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    /// A number identifying the connection among every connection accepted by the process.
+    pub id: u64,
+    /// The address of the client, or the original client if it connected through the PROXY protocol.
+    pub remote_addr: SocketAddr,
+    /// The common name of the client's verified TLS certificate, if it presented one.
+    pub client_subject: Option<Arc<str>>,
+}
+
+fn next_connection_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Connected<IncomingStream<'_, Listener>> for Connection {
+    fn connect_info(stream: IncomingStream<'_, Listener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+/// An accepted connection, either in plain text or over TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Plain(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A TCP listener that optionally reads PROXY protocol headers and terminates TLS.
+///
+/// Connections are accepted and their handshakes completed in the background, so slow clients
+/// can't hold up connections that are ready to be served, with at most `MAX_HANDSHAKES` in flight.
+pub struct Listener {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<(Stream, Connection)>,
+}
+
+impl Listener {
+    /// Bind to `address`, requiring a PROXY protocol header from every connection if
+    /// `proxy_protocol` is set, which is only accepted from the given trusted proxies.
+    pub async fn bind(
+        address: SocketAddr,
+        tls: Option<Arc<TlsConfig>>,
+        proxy_protocol: Option<Arc<[IpNet]>>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(BACKLOG);
+        let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let permit = handshakes
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the handshake semaphore is never closed");
+                let (stream, remote_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // Mirrors axum's handling, errors are usually caused by running out of
+                        // file descriptors so back off before trying again.
+                        error!("Failed to accept connection: {err}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let (sender, tls, proxy_protocol) =
+                    (sender.clone(), tls.clone(), proxy_protocol.clone());
+                tokio::spawn(async move {
+                    let handshake = accept(
+                        stream,
+                        remote_addr,
+                        tls.as_deref(),
+                        proxy_protocol.as_deref(),
+                    );
+                    let result = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await;
+                    drop(permit);
+                    match result {
+                        Ok(Ok(accepted)) => {
+                            let _ = sender.send(accepted).await;
+                        }
+                        Ok(Err(err)) => debug!("Handshake with {remote_addr} failed: {err:#}"),
+                        Err(_) => debug!("Handshake with {remote_addr} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            connections,
+        })
+    }
+}
+
+async fn accept(
+    mut stream: TcpStream,
+    mut remote_addr: SocketAddr,
+    tls: Option<&TlsConfig>,
+    proxy_protocol: Option<&[IpNet]>,
+) -> Result<(Stream, Connection)> {
+    if let Some(trusted_proxies) = proxy_protocol {
+        if !trusted_proxies
+            .iter()
+            .any(|network| network.contains(&remote_addr.ip().to_canonical()))
+        {
+            bail!("Only trusted proxies may connect when the PROXY protocol is enabled");
+        }
+        if let Some(source) = proxy_protocol::read_header(&mut stream).await? {
+            remote_addr = source;
+        }
+    }
+    let Some(tls) = tls else {
+        return Ok((
+            Stream::Plain(stream),
+            Connection {
+                id: next_connection_id(),
+                remote_addr,
+                client_subject: None,
+            },
+        ));
+    };
+    let stream = tls.acceptor().accept(stream).await?;
+    let client_subject = tls::client_subject(stream.get_ref().1.peer_certificates());
+    Ok((
+        Stream::Tls(Box::new(stream)),
+        Connection {
+            id: next_connection_id(),
+            remote_addr,
+            client_subject,
+        },
+    ))
+}
+
+impl axum::serve::Listener for Listener {
+    type Io = Stream;
+    type Addr = Connection;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(Connection {
+            id: 0,
+            remote_addr: self.local_addr,
+            client_subject: None,
+        })
+    }
+}
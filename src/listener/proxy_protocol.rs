@@ -0,0 +1,198 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The signature every version 2 header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read a PROXY protocol (version 1 or 2) header from the start of a stream, returning the
+/// original client address it carries.
+///
+/// Only the header is consumed so the rest of the stream can be read as usual. `None` is returned
+/// for headers that don't carry an address, such as health checks sent by the proxy itself.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least as long as the version 2 signature.
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    // Read a byte at a time so nothing past the header is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not valid UTF-8"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("Invalid PROXY protocol source address"))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| invalid("Invalid PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY protocol header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, length @ ..] = header;
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    let mut payload = vec![0; u16::from_be_bytes(length).into()];
+    stream.read_exact(&mut payload).await?;
+
+    // The `LOCAL` command is used for connections made by the proxy itself.
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    match family >> 4 {
+        // AF_INET: source address, destination address, source port, destination port.
+        0x1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).unwrap());
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6: as above with 16 byte addresses.
+        0x2 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("Truncated PROXY protocol address")),
+        // AF_UNSPEC and AF_UNIX don't carry an IP address.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address read from a header followed by a request, checking the request is left unread.
+    async fn read(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let stream = [header, b"GET / HTTP/1.1\r\n"].concat();
+        let mut stream = stream.as_slice();
+        let address = read_header(&mut stream).await?;
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+        Ok(address)
+    }
+
+    /// A version 2 header with a command, family and payload.
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let length = u16::try_from(payload.len()).unwrap().to_be_bytes();
+        [
+            &V2_SIGNATURE[..],
+            &[0x20 | command, family],
+            &length,
+            payload,
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn v1_addresses() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+                .await
+                .unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_addresses() {
+        let ipv4 = [[192, 0, 2, 1], [198, 51, 100, 1]].concat();
+        let ports = [56324u16.to_be_bytes(), 443u16.to_be_bytes()].concat();
+        assert_eq!(
+            read(&v2(1, 0x11, &[&ipv4[..], &ports].concat()))
+                .await
+                .unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        let ipv6 = [
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets(),
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap().octets(),
+        ]
+        .concat();
+        // Trailing TLVs are consumed along with the addresses.
+        assert_eq!(
+            read(&v2(
+                1,
+                0x21,
+                &[&ipv6[..], &ports, &[0x04, 0, 1, 0]].concat()
+            ))
+            .await
+            .unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(&v2(0, 0x11, &[&ipv4[..], &ports].concat()))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(read(&v2(1, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        for header in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 example.com 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            &v2(1, 0x11, &[192, 0, 2, 1]),
+        ] {
+            let mut stream = header;
+            assert_eq!(
+                read_header(&mut stream).await.unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{header:?}"
+            );
+        }
+        let mut too_long = [&b"PROXY TCP4 "[..], &[b'1'; V1_MAX_LENGTH]].concat();
+        too_long.extend(b"\r\n");
+        assert!(read_header(&mut too_long.as_slice()).await.is_err());
+        let mut version_1 = v2(1, 0x11, &[]);
+        version_1[12] = 0x11;
+        assert!(read_header(&mut version_1.as_slice()).await.is_err());
+    }
+}
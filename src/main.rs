@@ -1,38 +1,97 @@
+mod access_log;
+mod auth;
+mod config;
+mod healthcheck;
+mod hosts;
+mod ip_filter;
+mod limits;
+mod listener;
+mod metrics;
+mod mounts;
+mod request_context;
 mod routes;
+mod rules;
 mod storage;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tenants;
+#[cfg(test)]
+mod test_support;
+mod tls;
 
-use anyhow::Result;
+use access_log::{AccessLog, AccessLogFormat, ByteSize, Rotation};
+use anyhow::{Context, Result, anyhow, bail};
+use auth::{
+    AnonymousAccess, Authenticator, COOKIE_NAME, Grant, JwtConfig, SigningKey, normalize_path,
+};
 use axum::{
     Router,
+    body::Body,
     extract::Request,
-    http::{HeaderValue, header},
+    http::{HeaderName, HeaderValue, Method, Uri, header},
     middleware::{self as axum_middleware, Next},
-    routing::{get, head},
+    routing::{get, head, put},
+};
+use clap::{
+    ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind,
 };
-use clap::Parser;
 use clap_duration::duration_range_value_parse;
+use config::{Config, HumanDuration, Parsed, STORAGE_FLAG, SiteConfig, SiteSettings};
 use dotenvy::dotenv;
 use duration_human::{DurationHuman, DurationHumanValidator};
-use routes::{get_file_handler, get_file_root_handler, head_file_handler, head_file_root_handler};
-use std::{net::SocketAddr, time::Duration};
+use futures_util::future::try_join_all;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hosts::{HostPattern, Hosts, request_host};
+use ip_filter::{IpRules, parse_network};
+use ipnet::IpNet;
+use limits::{Bandwidth, PrefixRate, RateLimit, RateLimiter};
+use listener::{Connection, Listener};
+use mounts::{Mount, Mounts};
+use request_context::X_REQUEST_ID;
+use routes::{
+    CompressionLevel, ContentEncoding, delete_file_handler, encode_path, get_file_handler,
+    get_file_root_handler, head_file_handler, head_file_root_handler, healthz_handler,
+    move_file_handler, put_file_handler, readyz_all_handler, readyz_handler,
+};
+use rules::Rules;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use storage::StorageBackend;
+use tenants::{TenantMount, TenantStorage, Tenants};
+use tls::{CertificatePair, ClientAuth, TlsConfig};
 use tokio::{net::TcpListener, signal};
+use tower::Layer;
 use tower_http::{
     catch_panic::CatchPanicLayer,
-    normalize_path::NormalizePathLayer,
-    trace::{DefaultMakeSpan, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
-use tracing::{Level, info};
-use tracing_subscriber::EnvFilter;
+use tracing::{Level, field, info, info_span};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Clone, Parser)]
-#[clap(author, about, version)]
+#[clap(author, about, version, subcommand_negates_reqs = true)]
 struct Arguments {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A TOML or YAML file declaring storage backends and the sites served from them. Flags given on the command line or through the environment override its settings.
+    #[arg(long = "config", env = "HERMES_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Internet socket address that the server should be ran on.
     #[arg(
         long = "address",
         env = "HERMES_ADDRESS",
-        default_value = "127.0.0.1:8080"
+        default_value = "127.0.0.1:8080",
+        global = true
     )]
     address: SocketAddr,
 
@@ -41,8 +100,14 @@ struct Arguments {
     /// Available options depend on what was enabled at compile time, a full list of backends is below.
     ///
     /// Backends: `fs://<path>`, `s3://bucket`, `sshfs://<mountpoint>`
-    #[arg(long = "storage", env = "HERMES_STORAGE_BACKEND")]
-    storage: StorageBackend,
+    ///
+    /// The backend is only set up when serving files, so subcommands never touch storage.
+    #[arg(
+        long = "storage",
+        env = "HERMES_STORAGE_BACKEND",
+        required_unless_present = "config"
+    )]
+    storage: Option<String>,
 
     /// The duration of time to tell clients to cache files for.
     #[clap(long = "file-cache-duration", env = "HERMES_FILE_CACHE_DURATION", value_parser = duration_range_value_parse!(min: 1min, max: 100years))]
@@ -55,38 +120,893 @@ struct Arguments {
         default_value_t = 64000 // 64kb
     )]
     file_stream_buffersize: usize,
+
+    /// Comma-separated file names to look for, in order, when a directory is requested.
+    #[clap(
+        long = "index-files",
+        env = "HERMES_INDEX_FILES",
+        value_delimiter = ',',
+        default_value = "index.html"
+    )]
+    index_files: Vec<String>,
+
+    /// Permanently redirect requests for directories without a trailing slash to the trailing-slash form.
+    #[clap(
+        long = "redirect-directories",
+        env = "HERMES_REDIRECT_DIRECTORIES",
+        default_value_t = false
+    )]
+    redirect_directories: bool,
+
+    /// Comma-separated encodings of precompressed sibling files (e.g. `app.js.br`) to serve in place of the requested file, in order of preference.
+    ///
+    /// Encodings: `br`, `zstd`, `gzip`
+    #[clap(
+        long = "precompressed",
+        env = "HERMES_PRECOMPRESSED",
+        value_delimiter = ','
+    )]
+    precompressed: Vec<ContentEncoding>,
+
+    /// Comma-separated encodings to compress responses with on-the-fly when no precompressed sibling is available, in order of preference.
+    ///
+    /// Encodings: `br`, `zstd`, `gzip`
+    #[clap(
+        long = "compression",
+        env = "HERMES_COMPRESSION",
+        value_delimiter = ','
+    )]
+    compression: Vec<ContentEncoding>,
+
+    /// The level to compress responses with on-the-fly, either `fastest`, `default`, `best` or an algorithm-specific number.
+    #[clap(
+        long = "compression-level",
+        env = "HERMES_COMPRESSION_LEVEL",
+        default_value = "default"
+    )]
+    compression_level: CompressionLevel,
+
+    /// The minimum size (in bytes) a file must be to be compressed on-the-fly.
+    #[clap(
+        long = "compression-min-size",
+        env = "HERMES_COMPRESSION_MIN_SIZE",
+        default_value_t = 1024
+    )]
+    compression_min_size: usize,
+
+    /// Comma-separated glob patterns of content types that may be compressed on-the-fly.
+    #[clap(
+        long = "compression-types",
+        env = "HERMES_COMPRESSION_TYPES",
+        value_delimiter = ',',
+        default_value = "text/*,application/json,application/javascript,application/xml,image/svg+xml"
+    )]
+    compression_types: Vec<Glob>,
+
+    /// Render a listing of a directory's contents when it is requested and has no index file.
+    #[clap(long = "autoindex", env = "HERMES_AUTOINDEX", default_value_t = false)]
+    autoindex: bool,
+
+    /// Comma-separated glob patterns of entry names to hide from directory listings, e.g. `.*,*.bak`.
+    #[clap(
+        long = "autoindex-hide",
+        env = "HERMES_AUTOINDEX_HIDE",
+        value_delimiter = ','
+    )]
+    autoindex_hide: Vec<Glob>,
+
+    /// The maximum number of entries to fetch from storage for a single page of a directory listing.
+    #[clap(
+        long = "autoindex-page-size",
+        env = "HERMES_AUTOINDEX_PAGE_SIZE",
+        default_value_t = 1000
+    )]
+    autoindex_page_size: usize,
+
+    /// A bearer token with write access to every path, shorthand for a single entry in the tokens file.
+    #[clap(long = "write-token", env = "HERMES_WRITE_TOKEN")]
+    write_token: Option<String>,
+
+    /// A file of bearer tokens to authenticate requests with, one `<token> [read|write] [path globs]` entry per line.
+    #[clap(long = "auth-tokens-file", env = "HERMES_AUTH_TOKENS_FILE")]
+    auth_tokens_file: Option<PathBuf>,
+
+    /// An htpasswd file of bcrypt or argon2 hashed users to authenticate requests with via Basic authentication.
+    #[clap(long = "auth-htpasswd-file", env = "HERMES_AUTH_HTPASSWD_FILE")]
+    auth_htpasswd_file: Option<PathBuf>,
+
+    /// A JWKS file of HS256, RS256 or EdDSA keys to verify bearer JWTs with. Tokens are authorized against their `paths` and `ops` claims.
+    #[clap(long = "auth-jwks-file", env = "HERMES_AUTH_JWKS_FILE")]
+    auth_jwks_file: Option<PathBuf>,
+
+    /// The issuer (`iss` claim) JWTs must have been issued by.
+    #[clap(
+        long = "auth-jwt-issuer",
+        env = "HERMES_AUTH_JWT_ISSUER",
+        requires = "auth_jwks_file"
+    )]
+    auth_jwt_issuer: Option<String>,
+
+    /// The audience (`aud` claim) JWTs must have been issued for.
+    #[clap(
+        long = "auth-jwt-audience",
+        env = "HERMES_AUTH_JWT_AUDIENCE",
+        requires = "auth_jwks_file"
+    )]
+    auth_jwt_audience: Option<String>,
+
+    /// A file of TLS client certificate subjects (common names) to authenticate requests with, one `<subject> [read|write] [path globs]` entry per line.
+    #[clap(long = "auth-client-certs-file", env = "HERMES_AUTH_CLIENT_CERTS_FILE")]
+    auth_client_certs_file: Option<PathBuf>,
+
+    /// The access granted to unauthenticated requests when authentication is enabled, either `none` or `read`. Defaults to `read` when the write token is the only credential, and `none` otherwise.
+    #[clap(long = "auth-anonymous-access", env = "HERMES_AUTH_ANONYMOUS_ACCESS")]
+    auth_anonymous_access: Option<AnonymousAccess>,
+
+    /// Comma-separated `<key id>:<secret>` keys to sign and verify signed links and cookies with. The first key is used to sign and every key is accepted when verifying, so keys can be rotated by adding a new key to the front.
+    #[clap(
+        long = "signing-keys",
+        env = "HERMES_SIGNING_KEYS",
+        value_delimiter = ',',
+        global = true
+    )]
+    signing_keys: Vec<SigningKey>,
+
+    /// A file of `<path prefix> allow|deny <networks>` rules limiting which client addresses may access each path prefix.
+    #[clap(long = "ip-rules-file", env = "HERMES_IP_RULES_FILE")]
+    ip_rules_file: Option<PathBuf>,
+
+    /// Comma-separated addresses or CIDR networks of proxies trusted to report the client's address through the `Forwarded` or `X-Forwarded-For` headers.
+    #[clap(
+        long = "trusted-proxies",
+        env = "HERMES_TRUSTED_PROXIES",
+        value_delimiter = ',',
+        value_parser = parse_network
+    )]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Require a PROXY protocol (v1 or v2) header from every connection, which must be made by one of the trusted proxies.
+    #[clap(
+        long = "proxy-protocol",
+        env = "HERMES_PROXY_PROTOCOL",
+        default_value_t = false,
+        requires = "trusted_proxies"
+    )]
+    proxy_protocol: bool,
+
+    /// The number of requests each client may make over a period, e.g. `100/min`. Clients are identified by their authenticated identity, or otherwise their address.
+    #[clap(long = "rate-limit", env = "HERMES_RATE_LIMIT")]
+    rate_limit: Option<RateLimit>,
+
+    /// The number of requests a client may make at once before being rate limited, defaults to the number of requests in the rate limit.
+    #[clap(
+        long = "rate-limit-burst",
+        env = "HERMES_RATE_LIMIT_BURST",
+        requires = "rate_limit"
+    )]
+    rate_limit_burst: Option<u32>,
+
+    /// Comma-separated `[<path prefix>=]<bytes per second>` limits on the total rate responses are sent at, e.g. `100MiB,/releases=20MiB`.
+    #[clap(
+        long = "bandwidth-limit",
+        env = "HERMES_BANDWIDTH_LIMIT",
+        value_delimiter = ','
+    )]
+    bandwidth_limit: Vec<PrefixRate>,
+
+    /// Comma-separated `[<path prefix>=]<bytes per second>` limits on the rate responses are sent at over each connection.
+    #[clap(
+        long = "bandwidth-limit-per-connection",
+        env = "HERMES_BANDWIDTH_LIMIT_PER_CONNECTION",
+        value_delimiter = ','
+    )]
+    bandwidth_limit_per_connection: Vec<PrefixRate>,
+
+    /// Comma-separated `<cert path>:<key path>` PEM certificates to serve HTTPS with, picked by the hostname clients request through SNI. The first certificate is used when none match.
+    #[clap(
+        long = "tls-certificates",
+        env = "HERMES_TLS_CERTIFICATES",
+        value_delimiter = ','
+    )]
+    tls_certificates: Vec<CertificatePair>,
+
+    /// A PEM bundle of CA certificates to verify client certificates against, enabling mutual TLS.
+    #[clap(
+        long = "tls-client-ca",
+        env = "HERMES_TLS_CLIENT_CA",
+        requires = "tls_certificates"
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Whether clients must present a certificate signed by the client CA to connect, either `required` or `optional`.
+    #[clap(
+        long = "tls-client-auth",
+        env = "HERMES_TLS_CLIENT_AUTH",
+        default_value = "required"
+    )]
+    tls_client_auth: ClientAuth,
+
+    /// Internet socket address of a plain HTTP listener that redirects every request to HTTPS.
+    #[clap(
+        long = "tls-redirect-address",
+        env = "HERMES_TLS_REDIRECT_ADDRESS",
+        requires = "tls_certificates"
+    )]
+    tls_redirect_address: Option<SocketAddr>,
+
+    /// The duration of time to tell clients to only connect over HTTPS for through `Strict-Transport-Security`.
+    #[clap(long = "tls-hsts-max-age", env = "HERMES_TLS_HSTS_MAX_AGE", requires = "tls_certificates", value_parser = duration_range_value_parse!(min: 1s, max: 100years))]
+    tls_hsts_max_age: Option<DurationHuman>,
+
+    /// Expose Prometheus metrics at `/metrics`, on the admin listener if there is one and otherwise on the main listener subject to the same authentication and IP rules as files.
+    #[clap(long = "metrics", env = "HERMES_METRICS", default_value_t = false)]
+    metrics: bool,
+
+    /// Internet socket address of a separate plain HTTP admin listener serving `/healthz`, `/readyz` and `/metrics`.
+    #[clap(long = "admin-address", env = "HERMES_ADMIN_ADDRESS", global = true)]
+    admin_address: Option<SocketAddr>,
+
+    /// Write an access log line for every request in the given format, either `combined` or `json`.
+    #[clap(long = "access-log", env = "HERMES_ACCESS_LOG")]
+    access_log: Option<AccessLogFormat>,
+
+    /// A file to write the access log to rather than stdout.
+    #[clap(
+        long = "access-log-file",
+        env = "HERMES_ACCESS_LOG_FILE",
+        requires = "access_log"
+    )]
+    access_log_file: Option<PathBuf>,
+
+    /// The size the access log file may grow to before it is rotated, e.g. `100MiB`.
+    #[clap(
+        long = "access-log-max-size",
+        env = "HERMES_ACCESS_LOG_MAX_SIZE",
+        requires = "access_log_file"
+    )]
+    access_log_max_size: Option<ByteSize>,
+
+    /// The duration of time after which the access log file is rotated.
+    #[clap(long = "access-log-rotate-interval", env = "HERMES_ACCESS_LOG_ROTATE_INTERVAL", requires = "access_log_file", value_parser = duration_range_value_parse!(min: 1min, max: 100years))]
+    access_log_rotate_interval: Option<DurationHuman>,
+
+    /// The number of rotated access log files to keep, as `<file>.1` (the most recent) to `<file>.<n>`.
+    #[clap(
+        long = "access-log-max-files",
+        env = "HERMES_ACCESS_LOG_MAX_FILES",
+        default_value_t = 7
+    )]
+    access_log_max_files: usize,
+
+    /// Add a `Server-Timing` header to responses with the time spent on storage metadata lookups, opening files and writing, and handling the request in total.
+    #[clap(
+        long = "server-timing",
+        env = "HERMES_SERVER_TIMING",
+        default_value_t = false
+    )]
+    server_timing: bool,
+
+    /// The endpoint of an OpenTelemetry collector to export request and storage operation spans to, e.g. `http://localhost:4317`.
+    #[cfg(feature = "otel")]
+    #[clap(long = "otel-endpoint", env = "HERMES_OTEL_ENDPOINT")]
+    otel_endpoint: Option<String>,
+
+    /// The protocol to export spans to the collector over, either `grpc` or `http`.
+    #[cfg(feature = "otel")]
+    #[clap(
+        long = "otel-protocol",
+        env = "HERMES_OTEL_PROTOCOL",
+        default_value = "grpc"
+    )]
+    otel_protocol: telemetry::OtelProtocol,
+
+    /// The service name to export spans under.
+    #[cfg(feature = "otel")]
+    #[clap(
+        long = "otel-service-name",
+        env = "HERMES_OTEL_SERVICE_NAME",
+        default_value = env!("CARGO_PKG_NAME")
+    )]
+    otel_service_name: String,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Generate a signed link or cookie that grants temporary access to a path, using the first signing key.
+    Sign(SignArguments),
+
+    /// Check that a running server is ready to serve requests, exiting with a non-zero status if it isn't. Useful as a container `HEALTHCHECK`.
+    Healthcheck(HealthcheckArguments),
+
+    /// Inspect the rewrite and redirect rules of the configuration file's sites.
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum RulesCommand {
+    /// Print which rule of a site matches a URL and what it does to requests for it, exiting with a non-zero status if none does.
+    Test(RulesTestArguments),
 }
 
+#[derive(Debug, Clone, Args)]
+struct SignArguments {
+    /// The path to grant access to, e.g. `/private/report.pdf`.
+    path: String,
+
+    /// How long the link or cookie is valid for.
+    #[arg(long = "expires", value_parser = duration_range_value_parse!(min: 1s, max: 100years))]
+    expires: DurationHuman,
+
+    /// Grant access to every path beneath `path` rather than only `path` itself.
+    #[arg(long = "prefix")]
+    prefix: bool,
+
+    /// Only allow the link or cookie to be used from this client address.
+    #[arg(long = "ip")]
+    ip: Option<IpAddr>,
+
+    /// Only allow this (uppercase) method to be used, defaults to `GET` and `HEAD`.
+    #[arg(long = "method")]
+    method: Option<Method>,
+
+    /// Generate a `Set-Cookie` header value rather than a link, cookies always cover every path beneath `path`.
+    #[arg(long = "cookie")]
+    cookie: bool,
+
+    /// The base URL to generate the link for, e.g. `https://files.example.com`.
+    #[arg(long = "base-url")]
+    base_url: Option<String>,
+
+    /// Sign with the keys of this site of the configuration file rather than --signing-keys.
+    #[arg(long = "site")]
+    site: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct HealthcheckArguments {
+    /// Only check that the server is alive (`/healthz`) rather than ready to serve requests (`/readyz`).
+    #[arg(long = "liveness")]
+    liveness: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct RulesTestArguments {
+    /// The URL to test, e.g. `https://docs.example.com/v1/guide.html`, or just its path.
+    url: Uri,
+
+    /// The (uppercase) method of the request.
+    #[arg(long = "method", default_value = "GET")]
+    method: Method,
+
+    /// The site whose rules to test, defaults to the site serving the URL's host.
+    #[arg(long = "site")]
+    site: Option<String>,
+}
+
+/// The name of the site served when there's no configuration file.
+const DEFAULT_SITE: &str = "default";
+
 #[derive(Clone)]
 struct AppState {
-    storage: StorageBackend,
-    file_cache_duration: Option<Duration>,
-    file_stream_buffersize: usize,
+    mounts: Arc<Mounts>,
+    index_files: Arc<[Box<str>]>,
+    redirect_directories: bool,
+    precompressed: Arc<[ContentEncoding]>,
+    compression: Arc<[ContentEncoding]>,
+    compression_level: CompressionLevel,
+    compression_min_size: usize,
+    compression_types: Arc<GlobSet>,
+    autoindex: bool,
+    autoindex_hide: Arc<GlobSet>,
+    autoindex_page_size: usize,
+    auth: Option<Arc<Authenticator>>,
+    ip_rules: Arc<IpRules>,
+    trusted_proxies: Arc<[IpNet]>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    bandwidth: Option<Arc<Bandwidth>>,
+    access_log: Option<Arc<AccessLog>>,
+    server_timing: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")))
-        .init();
-    let args = Arguments::parse();
+    let matches = Arguments::command().get_matches();
+    let mut args = Arguments::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer());
+    // Spans are only exported while serving files, never from subcommands.
+    #[cfg(feature = "otel")]
+    let tracer_provider = match (&args.command, &args.otel_endpoint) {
+        (None, Some(endpoint)) => Some(telemetry::tracer_provider(
+            endpoint,
+            args.otel_protocol,
+            &args.otel_service_name,
+        )?),
+        _ => None,
+    };
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(tracer_provider.as_ref().map(telemetry::layer));
+    subscriber.init();
+    match args.command.take() {
+        Some(Command::Sign(sign_args)) => {
+            let signing_keys = match (&sign_args.site, &args.config) {
+                (Some(name), Some(path)) => {
+                    let config = Config::load(path)?;
+                    match config.site(name)?.auth.signing_keys.clone() {
+                        Some(keys) => keys.into_iter().map(|Parsed(key)| key).collect(),
+                        None => args.signing_keys,
+                    }
+                }
+                (Some(_), None) => {
+                    bail!("Sites are declared in a configuration file, pass one with --config")
+                }
+                (None, _) => args.signing_keys,
+            };
+            return sign(sign_args, &signing_keys);
+        }
+        Some(Command::Healthcheck(healthcheck_args)) => {
+            let addresses = match args.admin_address {
+                Some(address) => BTreeSet::from([address]),
+                None if !args.tls_certificates.is_empty() || args.proxy_protocol => bail!(
+                    "Health checks can only be made over plain HTTP, configure an admin listener with --admin-address to check a server using TLS or the PROXY protocol"
+                ),
+                None => site_addresses(&args, &matches)?,
+            };
+            let path = match healthcheck_args.liveness {
+                true => "/healthz",
+                false => "/readyz",
+            };
+            for address in addresses {
+                healthcheck::check(address, path).await?;
+            }
+            return Ok(());
+        }
+        Some(Command::Rules {
+            command: RulesCommand::Test(test_args),
+        }) => return test_rules(test_args, args.config.as_deref()),
+        None => {}
+    }
+    let config = match &args.config {
+        Some(path) => Some(Config::load(path)?),
+        None => None,
+    };
+    let settings = SiteSettings::new(&args, &matches);
+    let (storages, templates, sites) = match config {
+        Some(mut config) => {
+            settings.resolve_config(&mut config, args.storage.as_deref());
+            let storages = config.open_storage()?;
+            let templates = config.storage_templates();
+            (
+                storages,
+                templates,
+                config.sites.into_iter().collect::<Vec<_>>(),
+            )
+        }
+        None => {
+            let storage = match args.storage.as_deref().map(str::parse::<StorageBackend>) {
+                Some(Ok(storage)) => storage,
+                Some(Err(err)) => Arguments::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("invalid value for '--storage': {err}"),
+                    )
+                    .exit(),
+                None => {
+                    unreachable!("the storage backend is required without a configuration file")
+                }
+            };
+            let site = settings.resolve(SiteConfig::default());
+            (
+                BTreeMap::from([(STORAGE_FLAG.to_owned(), storage)]),
+                BTreeMap::new(),
+                vec![(DEFAULT_SITE.to_owned(), site)],
+            )
+        }
+    };
+    let all_storage: Arc<[StorageBackend]> = storages.values().cloned().collect();
+
+    let compression_types = Arc::new(build_globset(&args.compression_types)?);
+    let precompressed: Arc<[ContentEncoding]> = args.precompressed.into();
+    let compression: Arc<[ContentEncoding]> = args.compression.into();
+    let ip_rules = match &args.ip_rules_file {
+        Some(path) => IpRules::parse(
+            &tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read IP rules file {path:?}"))?,
+        )
+        .with_context(|| format!("Failed to parse IP rules file {path:?}"))?,
+        None => IpRules::default(),
+    };
+    let ip_rules = Arc::new(ip_rules);
+    let trusted_proxies: Arc<[IpNet]> = args.trusted_proxies.into();
+    let rate_limiter = args
+        .rate_limit
+        .map(|limit| RateLimiter::new(limit, args.rate_limit_burst));
+    let bandwidth = Bandwidth::new(args.bandwidth_limit, args.bandwidth_limit_per_connection);
+
+    let access_log = match args.access_log {
+        Some(format) => Some(AccessLog::new(
+            format,
+            args.access_log_file,
+            Rotation {
+                max_size: args.access_log_max_size.map(|size| size.0),
+                interval: args.access_log_rotate_interval.as_ref().map(Duration::from),
+                max_files: args.access_log_max_files,
+            },
+        )?),
+        None => None,
+    };
+
+    let tls = TlsConfig::new(
+        args.tls_certificates,
+        args.tls_client_ca,
+        args.tls_client_auth,
+    )?;
+    if let Some(tls) = &tls {
+        tls.spawn_reloader();
+    }
+    let hsts = args.tls_hsts_max_age.as_ref().map(|max_age| {
+        HeaderValue::try_from(format!("max-age={}", Duration::from(max_age).as_secs()))
+            .expect("max-age is a valid header value")
+    });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let proxy_protocol = args.proxy_protocol.then(|| trusted_proxies.clone());
+    let redirect_listener = match args.tls_redirect_address {
+        Some(address) => Some(TcpListener::bind(address).await?),
+        None => None,
+    };
+    let admin_listener = match args.admin_address {
+        Some(address) => Some(TcpListener::bind(address).await?),
+        None => None,
+    };
+    // Metrics are served by every site when there's no admin listener to serve them instead.
+    let metrics_storage = (args.metrics && admin_listener.is_none()).then(|| all_storage.clone());
+
+    // Sites sharing an address are served by the same listener, which routes by host.
+    let mut addresses = BTreeMap::<SocketAddr, Hosts>::new();
+    // The HTTPS port serving each host, for redirects from plain HTTP.
+    let mut https_ports = Vec::new();
+    for (name, site) in sites {
+        let address = site.address.expect("every site falls back to --address");
+        // The site's storage is mounted at its root, with an empty prefix.
+        let mounts = iter::once((
+            String::new(),
+            site.storage
+                .as_ref()
+                .expect("every site has a storage backend"),
+            site.file_cache_duration,
+            site.file_stream_buffersize,
+        ))
+        .chain(site.mounts.iter().map(|(prefix, mount)| {
+            (
+                mounts::parse_prefix(prefix).expect("mount prefixes are validated"),
+                &mount.storage,
+                mount.file_cache_duration,
+                mount.file_stream_buffersize,
+            )
+        }))
+        .map(
+            |(prefix, storage, file_cache_duration, file_stream_buffersize)| TenantMount {
+                prefix,
+                storage: match templates.get(storage) {
+                    Some(template) => TenantStorage::Template(template.clone()),
+                    None => TenantStorage::Shared(
+                        storages
+                            .get(storage)
+                            .expect("every served storage backend is opened")
+                            .clone(),
+                    ),
+                },
+                file_cache_duration: file_cache_duration.map(|HumanDuration(duration)| duration),
+                file_stream_buffersize: file_stream_buffersize.unwrap_or_default(),
+            },
+        )
+        .collect::<Vec<_>>();
+        // Sites whose storage is a template are only mounted for each tenant as it is requested.
+        let shared_mounts = mounts
+            .iter()
+            .map(|mount| match &mount.storage {
+                TenantStorage::Shared(storage) => Some(Mount::new(
+                    &mount.prefix,
+                    storage.clone(),
+                    mount.file_cache_duration,
+                    mount.file_stream_buffersize,
+                )),
+                TenantStorage::Template(_) => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        let serves_tenants = shared_mounts.is_none();
+        let state = async {
+            let index_files = site.index_files.unwrap_or_default();
+            if let Some(name) = index_files.iter().find(|name| {
+                name.is_empty() || name.contains(['/', '\\']) || *name == "." || *name == ".."
+            }) {
+                bail!("Index file names must be a single path segment, got {name:?}");
+            }
+            let autoindex_hide = site
+                .autoindex_hide
+                .unwrap_or_default()
+                .into_iter()
+                .map(|Parsed(glob)| glob)
+                .collect::<Vec<_>>();
+            let auth = Authenticator::new(
+                site.auth.tokens_file,
+                site.auth.htpasswd_file,
+                site.auth.jwks_file.map(|jwks_file| JwtConfig {
+                    jwks_file,
+                    issuer: site.auth.jwt_issuer,
+                    audience: site.auth.jwt_audience,
+                }),
+                site.auth.client_certs_file,
+                site.auth.write_token,
+                site.auth
+                    .signing_keys
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|Parsed(key)| key)
+                    .collect(),
+                site.auth.anonymous_access,
+            )
+            .await?;
+            if let Some(auth) = &auth {
+                auth.spawn_reloader();
+            }
+            anyhow::Ok(AppState {
+                mounts: Arc::new(shared_mounts.map(Mounts::new).unwrap_or_default()),
+                index_files: index_files.into_iter().map(Into::into).collect(),
+                redirect_directories: site.redirect_directories.unwrap_or_default(),
+                precompressed: precompressed.clone(),
+                compression: compression.clone(),
+                compression_level: args.compression_level,
+                compression_min_size: args.compression_min_size,
+                compression_types: compression_types.clone(),
+                autoindex: site.autoindex.unwrap_or_default(),
+                autoindex_hide: Arc::new(build_globset(&autoindex_hide)?),
+                autoindex_page_size: site.autoindex_page_size.unwrap_or_default(),
+                auth,
+                ip_rules: ip_rules.clone(),
+                trusted_proxies: trusted_proxies.clone(),
+                rate_limiter: rate_limiter.clone(),
+                bandwidth: bandwidth.clone(),
+                access_log: access_log.clone(),
+                server_timing: args.server_timing,
+            })
+        }
+        .await
+        .with_context(|| format!("Failed to set up site {name:?}"))?;
+        let rules = Arc::new(Rules::new(site.rules));
+        let hosts = addresses.entry(address).or_default();
+        if serves_tenants {
+            let placeholder = site
+                .hosts
+                .iter()
+                .find_map(|Parsed(pattern)| match pattern {
+                    HostPattern::Template { placeholder, .. } => Some(placeholder),
+                    _ => None,
+                })
+                .expect("sites serving tenants have a host naming the tenant");
+            let tenants = Arc::new(Tenants::new(&name, placeholder, mounts, {
+                let (headers, hsts, metrics_storage) =
+                    (site.headers, hsts.clone(), metrics_storage.clone());
+                move |mounts| {
+                    site_router(
+                        AppState {
+                            mounts,
+                            ..state.clone()
+                        },
+                        headers.clone(),
+                        hsts.clone(),
+                        metrics_storage.clone(),
+                        rules.clone(),
+                    )
+                }
+            }));
+            for Parsed(pattern) in &site.hosts {
+                hosts
+                    .add_tenants(pattern, &name, &tenants)
+                    .map_err(|err| anyhow!("sites.{name}.hosts: {err} on {address}"))?;
+                info!(
+                    "Serving tenants of site {name:?} for host {pattern} on {scheme}://{address}"
+                );
+                https_ports.push((Some(pattern.clone()), address.port()));
+            }
+            continue;
+        }
+        let router = site_router(
+            state,
+            site.headers,
+            hsts.clone(),
+            metrics_storage.clone(),
+            rules,
+        );
+        for Parsed(pattern) in &site.hosts {
+            hosts
+                .add(pattern, &name, &router)
+                .map_err(|err| anyhow!("sites.{name}.hosts: {err} on {address}"))?;
+            info!("Serving site {name:?} for host {pattern} on {scheme}://{address}");
+            https_ports.push((Some(pattern.clone()), address.port()));
+        }
+        if site.hosts.is_empty() || site.default == Some(true) {
+            hosts
+                .set_default(&name, &router)
+                .map_err(|err| {
+                    anyhow!(
+                        "sites.{name}: {err} on {address}, only one site sharing an address may leave out `hosts` or set `default`"
+                    )
+                })?;
+            info!("Serving site {name:?} for any other host on {scheme}://{address}");
+            https_ports.push((None, address.port()));
+        }
+    }
+
+    let mut servers = Vec::new();
+    let health_router = Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_all_handler))
+        .with_state(all_storage.clone());
+    for (address, mut hosts) in addresses {
+        hosts.set_health(health_router.clone());
+        let listener = Listener::bind(address, tls.clone(), proxy_protocol.clone()).await?;
+        info!("Internal server started - listening on: {scheme}://{address}");
+        servers.push(
+            axum::serve(
+                listener,
+                hosts
+                    .into_router()
+                    .into_make_service_with_connect_info::<Connection>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .into_future(),
+        );
+    }
 
-    let tcp_listener = TcpListener::bind(args.address).await?;
-    let router = Router::new()
+    let redirect = async {
+        let Some(redirect_listener) = redirect_listener else {
+            return Ok(());
+        };
+        info!(
+            "Redirecting to HTTPS - listening on: http://{}",
+            redirect_listener.local_addr()?
+        );
+        axum::serve(redirect_listener, tls::redirect_router(https_ports))
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+    };
+    let admin = async {
+        let Some(admin_listener) = admin_listener else {
+            return Ok(());
+        };
+        info!(
+            "Admin server started - listening on: http://{}",
+            admin_listener.local_addr()?
+        );
+        let mut admin_router = Router::new()
+            .route("/healthz", get(healthz_handler))
+            .route("/readyz", get(readyz_all_handler));
+        if args.metrics {
+            admin_router = admin_router.route("/metrics", get(metrics::metrics_handler));
+        }
+        axum::serve(admin_listener, admin_router.with_state(all_storage))
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+    };
+    tokio::try_join!(try_join_all(servers), redirect, admin)?;
+
+    // Flush any spans that haven't been exported yet, which blocks until the exporter is done.
+    #[cfg(feature = "otel")]
+    if let Some(tracer_provider) = tracer_provider {
+        tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await??;
+    }
+
+    Ok(())
+}
+
+/// Build the router serving a site, along with every middleware.
+fn site_router(
+    state: AppState,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    hsts: Option<HeaderValue>,
+    metrics_storage: Option<Arc<[StorageBackend]>>,
+    rules: Arc<Rules>,
+) -> Router {
+    let mut router = Router::new()
         .route("/", get(get_file_root_handler))
         .route("/", head(head_file_root_handler))
         .route("/{*path}", get(get_file_handler))
-        .route("/{*path}", head(head_file_handler))
+        .route("/{*path}", head(head_file_handler));
+    // The write API is only mounted when writes can be authenticated.
+    if state.auth.is_some() {
+        router = router.route(
+            "/{*path}",
+            put(put_file_handler)
+                .delete(delete_file_handler)
+                .fallback(move_file_handler),
+        );
+    }
+    if let Some(storages) = metrics_storage {
+        router = router.route(
+            "/metrics",
+            get(metrics::metrics_handler).with_state(storages),
+        );
+    }
+    let router = router
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_bandwidth,
+        ))
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_rate,
+        ))
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_failed_attempts,
+        ))
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            ip_filter::filter,
+        ))
+        // Health checks are added after the access controls so orchestrators don't need credentials.
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            request_context::scope,
+        ))
+        .with_state(state.clone());
+    // Rules are applied before requests are routed, so rewritten requests are served like any other.
+    let router = match rules.is_empty() {
+        true => router,
+        false => Router::new().fallback_service(
+            axum_middleware::from_fn_with_state(rules, rules::apply).layer(router),
+        ),
+    };
+    #[cfg(feature = "otel")]
+    let router = router.layer(axum_middleware::from_fn(telemetry::trace_response));
+    let router = router
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(|req: &Request| {
+                    // The subject is recorded once the request has been authenticated.
+                    let span = info_span!(
+                        "request",
+                        method = %req.method(),
+                        uri = %req.uri(),
+                        version = ?req.version(),
+                        request_id = req
+                            .headers()
+                            .get(X_REQUEST_ID)
+                            .and_then(|value| value.to_str().ok()),
+                        subject = field::Empty,
+                    );
+                    #[cfg(feature = "otel")]
+                    telemetry::set_parent(&span, req.headers());
+                    span
+                })
                 .on_request(DefaultOnRequest::default())
                 .on_response(DefaultOnResponse::default().level(Level::INFO))
                 .on_failure(DefaultOnFailure::default()),
         )
-        .layer(NormalizePathLayer::trim_trailing_slash())
         .layer(CatchPanicLayer::new())
+        .layer(axum_middleware::from_fn(metrics::record))
+        .layer(SetResponseHeaderLayer::overriding(
+            header::STRICT_TRANSPORT_SECURITY,
+            hsts,
+        ));
+    headers
+        .into_iter()
+        .fold(router, |router, (name, value)| {
+            router.layer(SetResponseHeaderLayer::overriding(name, value))
+        })
         .layer(axum_middleware::from_fn(
             async |req: Request, next: Next| {
                 let mut res = next.run(req).await;
@@ -99,24 +1019,116 @@ async fn main() -> Result<()> {
                 res
             },
         ))
-        .with_state(AppState {
-            storage: args.storage,
-            file_cache_duration: args.file_cache_duration.as_ref().map(Duration::from),
-            file_stream_buffersize: args.file_stream_buffersize,
-        });
+        .layer(axum_middleware::from_fn_with_state(state, access_log::log))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
 
-    info!(
-        "Internal server started - listening on: http://{}",
-        args.address,
-    );
+/// Print a link or `Set-Cookie` header value that grants temporary access to a path.
+fn sign(args: SignArguments, signing_keys: &[SigningKey]) -> Result<()> {
+    let Some(key) = signing_keys.first() else {
+        bail!("A signing key must be configured with --signing-keys to sign links");
+    };
+    let expires = SystemTime::now() + Duration::from(&args.expires);
+    let grant = Grant {
+        path: normalize_path(&args.path),
+        prefix: args.prefix || args.cookie,
+        expires: expires.duration_since(UNIX_EPOCH)?.as_secs(),
+        ip: args.ip,
+        method: args.method,
+    };
+    let params = grant.sign(key);
+    let path = encode_path(&grant.path);
+    if args.cookie {
+        println!(
+            "{COOKIE_NAME}={params}; Path={path}; Expires={}; HttpOnly; Secure; SameSite=Lax",
+            httpdate::fmt_http_date(expires)
+        );
+    } else {
+        let base_url = args.base_url.unwrap_or_default();
+        println!("{}{path}?{params}", base_url.trim_end_matches('/'));
+    }
+    Ok(())
+}
 
-    axum::serve(tcp_listener, router)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+/// The addresses the sites are served on, without opening their storage backends.
+fn site_addresses(args: &Arguments, matches: &ArgMatches) -> Result<BTreeSet<SocketAddr>> {
+    let Some(path) = &args.config else {
+        return Ok(BTreeSet::from([args.address]));
+    };
+    let settings = SiteSettings::new(args, matches);
+    Ok(Config::load(path)?
+        .sites
+        .into_values()
+        .filter_map(|site| settings.resolve(site).address)
+        .collect())
+}
 
+/// Print the first rule of a site matching a request for a URL, and what it does to the request.
+fn test_rules(args: RulesTestArguments, config: Option<&Path>) -> Result<()> {
+    let Some(path) = config else {
+        bail!("Rules are declared in a configuration file, pass one with --config");
+    };
+    let config = Config::load(path)?;
+    let req = axum::http::Request::builder()
+        .method(args.method)
+        .uri(args.url.clone())
+        .body(Body::empty())?;
+    let host = request_host(&req);
+    let (name, site) = match &args.site {
+        Some(name) => (name, config.site(name)?),
+        None => {
+            // Sites are chosen between the same way as when serving requests, falling back to
+            // those serving unmatched hosts.
+            let precedence = |site: &SiteConfig| {
+                let host = host.as_deref()?;
+                site.hosts
+                    .iter()
+                    .filter(|Parsed(pattern)| pattern.matches(host))
+                    .map(|Parsed(pattern)| pattern.precedence())
+                    .min()
+            };
+            let best = config.sites.values().filter_map(precedence).min();
+            let sites = config
+                .sites
+                .iter()
+                .filter(|(_, site)| match best {
+                    Some(_) => precedence(site) == best,
+                    None => site.hosts.is_empty() || site.default == Some(true),
+                })
+                .collect::<Vec<_>>();
+            let host = host.as_deref().unwrap_or("requests without a host");
+            match sites[..] {
+                [site] => site,
+                [] => bail!("No site serves {host}, choose one with --site"),
+                _ => bail!(
+                    "Sites {} could all serve {host}, choose one with --site",
+                    sites
+                        .iter()
+                        .map(|(name, _)| format!("{name:?}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+    };
+    let rules = Rules::new(site.rules.clone());
+    let Some((index, rule, outcome)) = rules.find(&req) else {
+        bail!("No rule of site {name:?} matches {}", args.url);
+    };
+    println!("sites.{name}.rules[{index}]: {rule}");
+    println!("{}", outcome.map_err(|err| anyhow!("Rule {err}"))?);
     Ok(())
 }
 
+fn build_globset(globs: &[Glob]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    Ok(builder.build()?)
+}
+
 // https://github.com/tokio-rs/axum/blob/15917c6dbcb4a48707a20e9cfd021992a279a662/examples/graceful-shutdown/src/main.rs#L55
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -141,3 +1153,219 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(all(test, feature = "storage-filesystem"))]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Method, StatusCode},
+        response::Response,
+    };
+    use test_support::TempDir;
+    use tower::ServiceExt;
+
+    /// The state of a site serving `hello.txt` from a temporary directory.
+    fn state(dir: &TempDir) -> AppState {
+        std::fs::write(dir.path().join("hello.txt"), "Hello, world!").unwrap();
+        let storage = format!("fs://{}", dir.path().display()).parse().unwrap();
+        AppState {
+            mounts: Arc::new(Mounts::new(vec![Mount::new("", storage, None, 1024)])),
+            index_files: Arc::new([]),
+            redirect_directories: false,
+            precompressed: Arc::new([]),
+            compression: Arc::new([]),
+            compression_level: CompressionLevel::Default,
+            compression_min_size: 0,
+            compression_types: Arc::new(GlobSet::empty()),
+            autoindex: false,
+            autoindex_hide: Arc::new(GlobSet::empty()),
+            autoindex_page_size: 100,
+            auth: None,
+            ip_rules: Arc::default(),
+            trusted_proxies: Arc::new([]),
+            rate_limiter: None,
+            bandwidth: None,
+            access_log: None,
+            server_timing: false,
+        }
+    }
+
+    fn key() -> SigningKey {
+        "k1:a-secret-of-sixteen-characters".parse().unwrap()
+    }
+
+    /// A link to `path` signed with `key`.
+    fn signed(path: &str) -> String {
+        let grant = Grant {
+            path: path.to_owned(),
+            prefix: false,
+            expires: u64::MAX,
+            ip: None,
+            method: None,
+        };
+        format!("{path}?{}", grant.sign(&key()))
+    }
+
+    async fn request(
+        router: &Router,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Response {
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Send the same request with `GET` and `HEAD`, checking both are answered alike and returning
+    /// the status and body of the `GET` response.
+    async fn get_and_head(
+        router: &Router,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, String) {
+        let get = request(router, Method::GET, uri, headers).await;
+        let head = request(router, Method::HEAD, uri, headers).await;
+        assert_eq!(get.status(), head.status(), "{uri} {headers:?}");
+        let comparable = |response: &Response| {
+            let mut headers = response.headers().clone();
+            // Generated for each request.
+            headers.remove(X_REQUEST_ID);
+            headers
+        };
+        assert_eq!(comparable(&get), comparable(&head), "{uri} {headers:?}");
+        let head_body = to_bytes(head.into_body(), usize::MAX).await.unwrap();
+        assert!(head_body.is_empty());
+        let status = get.status();
+        let body = to_bytes(get.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn head_answers_like_get() {
+        let dir = TempDir::new("head");
+        let router = site_router(state(&dir), Vec::new(), None, None, Arc::default());
+        assert_eq!(
+            get_and_head(&router, "/hello.txt", &[]).await,
+            (StatusCode::OK, "Hello, world!".to_owned())
+        );
+        // Range requests are only defined for GET, so HEAD describes the whole file.
+        let range = request(
+            &router,
+            Method::GET,
+            "/hello.txt",
+            &[("range", "bytes=0-4")],
+        )
+        .await;
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(range.headers()[header::CONTENT_RANGE], "bytes 0-4/13");
+        let head = request(
+            &router,
+            Method::HEAD,
+            "/hello.txt",
+            &[("range", "bytes=0-4")],
+        )
+        .await;
+        assert_eq!(head.status(), StatusCode::OK);
+        assert_eq!(head.headers()[header::CONTENT_LENGTH], "13");
+        let unsatisfiable = request(
+            &router,
+            Method::GET,
+            "/hello.txt",
+            &[("range", "bytes=100-")],
+        )
+        .await;
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            get_and_head(&router, "/hello.txt", &[("if-match", "\"other\"")])
+                .await
+                .0,
+            StatusCode::PRECONDITION_FAILED
+        );
+        let etag = request(&router, Method::GET, "/hello.txt", &[])
+            .await
+            .headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            get_and_head(&router, "/hello.txt", &[("if-none-match", &etag)])
+                .await
+                .0,
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(
+            get_and_head(&router, "/missing.txt", &[]).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_and_head(&router, "/archive/../hello.txt", &[]).await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_uncompressed() {
+        let dir = TempDir::new("compressed-ranges");
+        let state = AppState {
+            compression: Arc::new([ContentEncoding::Gzip]),
+            compression_types: Arc::new(build_globset(&["text/*".parse().unwrap()]).unwrap()),
+            ..state(&dir)
+        };
+        let router = site_router(state, Vec::new(), None, None, Arc::default());
+        let range = [("accept-encoding", "gzip"), ("range", "bytes=0-4")];
+        let get = request(&router, Method::GET, "/hello.txt", &range).await;
+        assert_eq!(get.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!get.headers().contains_key(header::CONTENT_ENCODING));
+        // HEAD ignores ranges, and so does GET once the file changed, so both are compressed.
+        let head = request(&router, Method::HEAD, "/hello.txt", &range).await;
+        assert_eq!(head.status(), StatusCode::OK);
+        assert_eq!(head.headers()[header::CONTENT_ENCODING], "gzip");
+        let stale = [range[0], range[1], ("if-range", "\"stale\"")];
+        let get = request(&router, Method::GET, "/hello.txt", &stale).await;
+        assert_eq!(get.status(), StatusCode::OK);
+        assert_eq!(get.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn signed_links_verify_before_rewrites() {
+        let dir = TempDir::new("rewrites");
+        std::fs::create_dir(dir.path().join("archive")).unwrap();
+        std::fs::write(dir.path().join("archive/old.txt"), "Old news").unwrap();
+        let state = AppState {
+            auth: Authenticator::new(None, None, None, None, None, vec![key()], None)
+                .await
+                .unwrap(),
+            ..state(&dir)
+        };
+        let rules = r#"
+            [[rules]]
+            path = "/news/*"
+            rewrite = "/archive/$1"
+        "#;
+        let rules = toml::from_str::<BTreeMap<String, Vec<rules::Rule>>>(rules).unwrap();
+        let rules = Rules::new(rules.into_values().next().unwrap_or_default());
+        let router = site_router(state, Vec::new(), None, None, Arc::new(rules));
+        assert_eq!(
+            get_and_head(&router, &signed("/news/old.txt"), &[]).await,
+            (StatusCode::OK, "Old news".to_owned())
+        );
+        assert_eq!(
+            get_and_head(
+                &router,
+                &signed("/archive/old.txt").replace("/archive/", "/news/"),
+                &[]
+            )
+            .await
+            .0,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::storage::StorageBackend;
+#[cfg(feature = "storage-s3")]
+use crate::storage::StorageError;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use http_body::{Frame, SizeHint};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, LazyLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::error;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct RequestLabels {
+    method: &'static str,
+    status: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct StorageLabels {
+    backend: &'static str,
+    operation: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct BackendLabels {
+    backend: &'static str,
+}
+
+#[cfg(feature = "storage-s3")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct ErrorLabels {
+    kind: &'static str,
+}
+
+/// The metrics collected by the server, exposed in the OpenMetrics text format.
+struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    response_bytes: Counter,
+    streams_in_flight: Gauge,
+    storage_duration: Family<StorageLabels, Histogram>,
+    #[cfg(feature = "storage-s3")]
+    s3_errors: Family<ErrorLabels, Counter>,
+    storage_mounted: Family<BackendLabels, Gauge>,
+    access_log_dropped_lines: Counter,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let mut registry = Registry::with_prefix("hermes");
+    let requests = Family::<RequestLabels, Counter>::default();
+    registry.register(
+        "http_requests",
+        "Requests handled by method and response status",
+        requests.clone(),
+    );
+    let response_bytes = Counter::default();
+    registry.register(
+        "http_response_body_bytes",
+        "Bytes of response bodies sent to clients",
+        response_bytes.clone(),
+    );
+    let streams_in_flight = Gauge::default();
+    registry.register(
+        "http_streams_in_flight",
+        "Response bodies currently being sent to clients",
+        streams_in_flight.clone(),
+    );
+    let storage_duration = Family::<StorageLabels, Histogram>::new_with_constructor(|| {
+        // 1ms up to ~16s.
+        Histogram::new(exponential_buckets(0.001, 2.0, 15))
+    });
+    registry.register(
+        "storage_operation_duration_seconds",
+        "Time taken by storage backend operations",
+        storage_duration.clone(),
+    );
+    #[cfg(feature = "storage-s3")]
+    let s3_errors = Family::<ErrorLabels, Counter>::default();
+    #[cfg(feature = "storage-s3")]
+    registry.register(
+        "s3_errors",
+        "Errors returned by S3 other than missing objects, by kind",
+        s3_errors.clone(),
+    );
+    let storage_mounted = Family::<BackendLabels, Gauge>::default();
+    registry.register(
+        "storage_mounted",
+        "Whether the remote filesystem of a mounted backend such as SSHFS is currently mounted",
+        storage_mounted.clone(),
+    );
+    let access_log_dropped_lines = Counter::default();
+    registry.register(
+        "access_log_dropped_lines",
+        "Access log lines dropped because the writer couldn't keep up",
+        access_log_dropped_lines.clone(),
+    );
+    Metrics {
+        registry,
+        requests,
+        response_bytes,
+        streams_in_flight,
+        storage_duration,
+        #[cfg(feature = "storage-s3")]
+        s3_errors,
+        storage_mounted,
+        access_log_dropped_lines,
+    }
+});
+
+/// Record the time taken by a storage operation against the backend it was performed by.
+pub fn observe_storage(backend: &'static str, operation: &'static str, duration: Duration) {
+    METRICS
+        .storage_duration
+        .get_or_create(&StorageLabels { backend, operation })
+        .observe(duration.as_secs_f64());
+}
+
+/// Count an error returned by S3. Missing objects are an expected outcome of probing for files
+/// rather than a failure, so they aren't counted.
+#[cfg(feature = "storage-s3")]
+pub fn record_s3_error(err: &StorageError) {
+    let kind = match err {
+        StorageError::NotFound => return,
+        StorageError::Forbidden(_) => "forbidden",
+        StorageError::InvalidPath(_) => "invalid_path",
+        StorageError::Unavailable(_) => "unavailable",
+        StorageError::Timeout(_) => "timeout",
+        StorageError::Backend(_) => "backend",
+    };
+    METRICS.s3_errors.get_or_create(&ErrorLabels { kind }).inc();
+}
+
+/// Count an access log line dropped because the writer couldn't keep up.
+pub fn record_dropped_access_log_line() {
+    METRICS.access_log_dropped_lines.inc();
+}
+
+/// A response body that counts the bytes sent through it and is counted as in flight until dropped.
+struct MeteredBody(Body);
+
+impl MeteredBody {
+    fn new(body: Body) -> Self {
+        METRICS.streams_in_flight.inc();
+        Self(body)
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        METRICS.streams_in_flight.dec();
+    }
+}
+
+impl http_body::Body for MeteredBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.0).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            METRICS.response_bytes.inc_by(data.len() as u64);
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
+
+/// Middleware that counts every response and the bytes of their bodies.
+pub async fn record(req: Request, next: Next) -> AxumResponse {
+    // Other methods are grouped together so clients can't create arbitrarily many series.
+    let method = match *req.method() {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::POST => "POST",
+        _ if req.method() == "MOVE" => "MOVE",
+        _ => "OTHER",
+    };
+    let (parts, body) = next.run(req).await.into_parts();
+    METRICS
+        .requests
+        .get_or_create(&RequestLabels {
+            method,
+            status: parts.status.as_u16(),
+        })
+        .inc();
+    AxumResponse::from_parts(parts, Body::new(MeteredBody::new(body)))
+}
+
+/// Serve the metrics in the OpenMetrics text format.
+pub async fn metrics_handler(State(storages): State<Arc<[StorageBackend]>>) -> AxumResponse {
+    // A backend is only reported as mounted if every storage using it is.
+    let mut mounted = HashMap::new();
+    for storage in storages.iter() {
+        if let Some(is_mounted) = storage.is_mounted() {
+            *mounted.entry(storage.name()).or_insert(true) &= is_mounted;
+        }
+    }
+    for (backend, mounted) in mounted {
+        METRICS
+            .storage_mounted
+            .get_or_create(&BackendLabels { backend })
+            .set(mounted.into());
+    }
+    let mut body = String::new();
+    if let Err(err) = encode(&mut body, &METRICS.registry) {
+        error!("Failed to encode metrics: {err}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE))],
+        body,
+    )
+        .into_response()
+}
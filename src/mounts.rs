@@ -0,0 +1,126 @@
+use crate::{
+    routes::encode_path,
+    storage::{StorageBackend, StorageKey},
+};
+use std::{sync::Arc, time::Duration};
+
+/// A storage backend served under a path prefix, with its own caching and streaming settings.
+#[derive(Clone)]
+pub struct Mount {
+    /// The normalised prefix without leading or trailing slashes, empty for the site's root.
+    prefix: Arc<str>,
+    pub storage: StorageBackend,
+    pub file_cache_duration: Option<Duration>,
+    pub file_stream_buffersize: usize,
+}
+
+impl Mount {
+    /// Mount a storage backend under `prefix`, which must have been normalised by [`parse_prefix`].
+    pub fn new(
+        prefix: &str,
+        storage: StorageBackend,
+        file_cache_duration: Option<Duration>,
+        file_stream_buffersize: usize,
+    ) -> Self {
+        Self {
+            prefix: prefix.into(),
+            storage,
+            file_cache_duration,
+            file_stream_buffersize,
+        }
+    }
+
+    /// Whether the mount serves the site's root rather than a path prefix.
+    pub fn is_root(&self) -> bool {
+        self.prefix.is_empty()
+    }
+
+    /// Whether both mounts are the same mount of a site.
+    pub fn is_same(&self, other: &Mount) -> bool {
+        self.prefix == other.prefix
+    }
+
+    /// The request path of a directory in the mount, with a trailing slash.
+    pub fn directory_path(&self, key: Option<&StorageKey>) -> String {
+        let mut path = String::from("/");
+        for part in [&*self.prefix, key.map_or("", StorageKey::as_str)] {
+            if !part.is_empty() {
+                path.push_str(&encode_path(part));
+                path.push('/');
+            }
+        }
+        path
+    }
+}
+
+/// Normalise a mount prefix such as `/releases/` to `releases`, rejecting prefixes that aren't a
+/// path below the site's root.
+pub fn parse_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.starts_with('/') || prefix.trim_matches('/').is_empty() {
+        return Err(format!(
+            "invalid mount prefix {prefix:?}, expected a path such as `/releases`"
+        ));
+    }
+    StorageKey::new(prefix.trim_start_matches('/'))
+        .map(|key| key.as_str().to_owned())
+        .map_err(|err| format!("invalid mount prefix {prefix:?}: {err}"))
+}
+
+/// The storage backends of a site by the path prefixes they are mounted under. Sites without a
+/// root mount only serve paths under their prefixes.
+#[derive(Default)]
+pub struct Mounts {
+    /// Mounts under a prefix, longest first so the most specific one matches.
+    prefixed: Box<[Mount]>,
+    root: Option<Mount>,
+}
+
+impl Mounts {
+    pub fn new(mounts: Vec<Mount>) -> Self {
+        let (mut root, mut prefixed): (Vec<_>, Vec<_>) =
+            mounts.into_iter().partition(Mount::is_root);
+        prefixed.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.split('/').count()));
+        Self {
+            prefixed: prefixed.into(),
+            root: root.pop(),
+        }
+    }
+
+    /// Find the mount serving a request path, along with the path within it.
+    ///
+    /// Prefixes are matched segment by segment, ignoring empty and `.` segments like storage keys
+    /// do, so a path can't reach a prefix's files through the root mount. The path within the
+    /// mount keeps any trailing slash of the request path, unless it is the mount's root.
+    pub fn resolve(&self, path: &str) -> Option<(&Mount, String)> {
+        let segments = path
+            .split('/')
+            .filter(|segment| !matches!(*segment, "" | "."))
+            .collect::<Vec<_>>();
+        let (mount, rest) = self
+            .prefixed
+            .iter()
+            .find_map(|mount| {
+                let depth = mount.prefix.split('/').count();
+                (segments.len() >= depth
+                    && segments[..depth]
+                        .iter()
+                        .copied()
+                        .eq(mount.prefix.split('/')))
+                .then(|| (mount, &segments[depth..]))
+            })
+            .or_else(|| Some((self.root.as_ref()?, &segments[..])))?;
+        let mut rest = rest.join("/");
+        if !rest.is_empty() && path.ends_with('/') {
+            rest.push('/');
+        }
+        Some((mount, rest))
+    }
+
+    /// The storage backends of every mount.
+    pub fn storages(&self) -> impl Iterator<Item = &StorageBackend> {
+        self.prefixed
+            .iter()
+            .chain(&self.root)
+            .map(|mount| &mount.storage)
+    }
+}
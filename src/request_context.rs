@@ -0,0 +1,138 @@
+use crate::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response as AxumResponse,
+};
+use core::fmt::Write as _;
+use http_body::{Frame, SizeHint};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+tokio::task_local! {
+    static CONTEXT: Arc<RequestContext>;
+}
+
+/// What storage backends need to know about the request being handled, without it being threaded
+/// through every storage operation.
+struct RequestContext {
+    #[cfg(feature = "storage-s3")]
+    id: Option<HeaderValue>,
+    timings: Mutex<StorageTimings>,
+}
+
+/// The total time spent in each kind of storage operation while handling a request, if any were
+/// performed.
+#[derive(Default)]
+struct StorageTimings {
+    metadata: Option<Duration>,
+    open: Option<Duration>,
+    write: Option<Duration>,
+}
+
+/// The `X-Request-Id` of the request being handled by the current task, if there is one.
+#[cfg(feature = "storage-s3")]
+pub fn request_id() -> Option<HeaderValue> {
+    CONTEXT
+        .try_with(|context| context.id.clone())
+        .ok()
+        .flatten()
+}
+
+/// Add the time taken by a storage operation to the timings of the request being handled by the
+/// current task.
+pub fn record_storage(operation: &'static str, duration: Duration) {
+    let _ = CONTEXT.try_with(|context| {
+        let mut timings = context.timings.lock().expect("timings lock poisoned");
+        let timing = match operation {
+            "metadata" | "list" => &mut timings.metadata,
+            "read_stream" | "read_range" => &mut timings.open,
+            "write_stream" | "delete" | "rename" => &mut timings.write,
+            _ => return,
+        };
+        *timing.get_or_insert_default() += duration;
+    });
+}
+
+/// Middleware that makes the request's ID available to storage backends and, if enabled, reports
+/// the time spent in storage and handling the request through a `Server-Timing` header.
+pub async fn scope(State(state): State<AppState>, req: Request, next: Next) -> AxumResponse {
+    let start = Instant::now();
+    let context = Arc::new(RequestContext {
+        #[cfg(feature = "storage-s3")]
+        id: req.headers().get(X_REQUEST_ID).cloned(),
+        timings: Mutex::default(),
+    });
+    let mut res = CONTEXT
+        .scope(context.clone(), next.run(req))
+        .await
+        .map(|body| {
+            Body::new(ScopedBody {
+                body,
+                context: context.clone(),
+            })
+        });
+    if state.server_timing {
+        let timings = context.timings.lock().expect("timings lock poisoned");
+        let mut value = String::new();
+        for (name, description, duration) in [
+            ("metadata", "Storage metadata", timings.metadata),
+            ("open", "Storage open", timings.open),
+            ("write", "Storage write", timings.write),
+        ] {
+            if let Some(duration) = duration {
+                let _ = write!(
+                    value,
+                    "{name};desc=\"{description}\";dur={:.3}, ",
+                    duration.as_secs_f64() * 1000.0
+                );
+            }
+        }
+        let _ = write!(
+            value,
+            "total;dur={:.3}",
+            start.elapsed().as_secs_f64() * 1000.0
+        );
+        if let Ok(value) = HeaderValue::try_from(value) {
+            res.headers_mut().insert(SERVER_TIMING, value);
+        }
+    }
+    res
+}
+
+/// A response body that is polled within its request's context, as bodies such as multipart
+/// ranges perform storage operations while they are streamed.
+struct ScopedBody {
+    body: Body,
+    context: Arc<RequestContext>,
+}
+
+impl http_body::Body for ScopedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let Self { body, context } = &mut *self;
+        CONTEXT.sync_scope(context.clone(), || Pin::new(body).poll_frame(cx))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
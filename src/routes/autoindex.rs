@@ -0,0 +1,286 @@
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{DirectoryEntry, StorageError, StorageKey, StorageOperations},
+};
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{HeaderMap, HeaderValue, Response, StatusCode, Uri, header},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use core::fmt::Write;
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use serde::Deserialize;
+use std::time::UNIX_EPOCH;
+
+/// Characters that must be escaped when a name is used as a single URL path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Characters that must be escaped when a value is used within a query string.
+const QUERY_COMPONENT: &AsciiSet = &PATH_SEGMENT.add(b'&').add(b'+').add(b'=').add(b';');
+
+#[derive(Debug, Default, Deserialize)]
+struct ListingQuery {
+    cursor: Option<String>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+impl SortOrder {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Render a page of the directory at `path` as HTML, or as JSON if the client accepts it.
+///
+/// Entries matching the configured hidden patterns are omitted and directories are always
+/// listed before files. Sorting only applies to the entries on the current page.
+pub async fn directory_listing(
+    key: Option<&StorageKey>,
+    uri: &Uri,
+    headers: &HeaderMap,
+    mount: &Mount,
+    state: &AppState,
+) -> Result<AxumResponse, StorageError> {
+    let query = Query::<ListingQuery>::try_from_uri(uri)
+        .map(|query| query.0)
+        .unwrap_or_default();
+
+    let listing = mount
+        .storage
+        .list(key, query.cursor.as_deref(), state.autoindex_page_size)
+        .await?;
+    let mut entries = listing
+        .entries
+        .into_iter()
+        .filter(|entry| !state.autoindex_hide.is_match(&*entry.name))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| {
+        let ordering = match query.sort {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.file_size.cmp(&b.file_size),
+            SortKey::Modified => a.last_modified.cmp(&b.last_modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = match query.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+
+    let base_path = mount.directory_path(key);
+    let next_cursor = listing.next_cursor.as_deref();
+
+    let accepts_json = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"));
+    let (content_type, body) = if accepts_json {
+        (
+            "application/json",
+            render_json(&base_path, &entries, next_cursor),
+        )
+    } else {
+        (
+            "text/html; charset=utf-8",
+            render_html(&base_path, base_path != "/", &entries, next_cursor, &query),
+        )
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, HeaderValue::from_static(content_type))
+        .header(header::VARY, HeaderValue::from_static("Accept"))
+        .body(Body::from(body))
+        .unwrap()
+        .into_response())
+}
+
+fn render_json(base_path: &str, entries: &[DirectoryEntry], next_cursor: Option<&str>) -> String {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": &*entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": (!entry.is_dir).then_some(entry.file_size),
+                "modified": entry
+                    .last_modified
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs()),
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "path": base_path,
+        "entries": entries,
+        "next_cursor": next_cursor,
+    })
+    .to_string()
+}
+
+fn render_html(
+    base_path: &str,
+    has_parent: bool,
+    entries: &[DirectoryEntry],
+    next_cursor: Option<&str>,
+    query: &ListingQuery,
+) -> String {
+    let title = format!("Index of {}", escape_html(base_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         th,td{{padding:.25em 1em;text-align:left}}td.size{{text-align:right}}</style>\n\
+         </head>\n<body>\n<h1>{title}</h1>\n<table>\n<thead><tr>"
+    );
+    for (sort, label) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Last modified"),
+    ] {
+        let order = match (query.sort == sort, query.order) {
+            (true, SortOrder::Asc) => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        };
+        let _ = write!(
+            html,
+            "<th><a href=\"?sort={}&amp;order={}\">{label}</a></th>",
+            sort.as_str(),
+            order.as_str()
+        );
+    }
+    html.push_str("</tr></thead>\n<tbody>\n");
+
+    if has_parent {
+        let parent = base_path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .map(|(parent, _)| format!("{parent}/"))
+            .unwrap_or_else(|| String::from("/"));
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>",
+            escape_html(&parent)
+        );
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let href = format!(
+            "{base_path}{}{suffix}",
+            utf8_percent_encode(&entry.name, PATH_SEGMENT)
+        );
+        let size = if entry.is_dir {
+            String::from("-")
+        } else {
+            human_size(entry.file_size)
+        };
+        let modified = entry
+            .last_modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">{}{suffix}</a></td><td class=\"size\">{size}</td><td>{modified}</td></tr>",
+            escape_html(&href),
+            escape_html(&entry.name),
+        );
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    if let Some(cursor) = next_cursor {
+        let _ = writeln!(
+            html,
+            "<p><a href=\"?cursor={}&amp;sort={}&amp;order={}\">Next page</a></p>",
+            utf8_percent_encode(cursor, QUERY_COMPONENT),
+            query.sort.as_str(),
+            query.order.as_str()
+        );
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Percent-encode each segment of a `/`-separated path.
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for char in value.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
@@ -0,0 +1,195 @@
+use super::encoding::{ContentEncoding, accepts_encoding};
+use crate::{AppState, storage::StorageKey};
+use async_compression::{
+    Level,
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+};
+use axum::http::HeaderMap;
+use core::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::{self, AsyncRead, BufReader, ReadBuf};
+use tracing::debug;
+
+/// The level of compression to use for on-the-fly compression.
+#[derive(Debug, Clone, Copy)]
+pub enum CompressionLevel {
+    Fastest,
+    Default,
+    Best,
+    /// An algorithm-specific level, e.g. 1-9 for gzip or 0-11 for brotli.
+    Precise(i32),
+}
+
+impl FromStr for CompressionLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "fastest" => Ok(Self::Fastest),
+            "default" => Ok(Self::Default),
+            "best" => Ok(Self::Best),
+            level => level.parse().map(Self::Precise).map_err(|_| {
+                format!("Expected 'fastest', 'default', 'best' or a number, got {level:?}")
+            }),
+        }
+    }
+}
+
+impl From<CompressionLevel> for Level {
+    fn from(level: CompressionLevel) -> Self {
+        match level {
+            CompressionLevel::Fastest => Level::Fastest,
+            CompressionLevel::Default => Level::Default,
+            CompressionLevel::Best => Level::Best,
+            CompressionLevel::Precise(level) => Level::Precise(level),
+        }
+    }
+}
+
+/// Pick an encoding to compress a response with on-the-fly, if any.
+///
+/// Compression is skipped for partial responses (as ranges apply to the identity representation),
+/// small files and content types outside of the configured allowlist.
+pub fn select_dynamic_encoding(
+    headers: &HeaderMap,
+    content_type: &str,
+    file_size: usize,
+    partial: bool,
+    state: &AppState,
+) -> Option<ContentEncoding> {
+    if state.compression.is_empty() || partial || file_size < state.compression_min_size {
+        return None;
+    }
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if !state.compression_types.is_match(essence) {
+        return None;
+    }
+    state
+        .compression
+        .iter()
+        .copied()
+        .find(|&encoding| accepts_encoding(headers, encoding))
+}
+
+/// Compress a file stream, logging the compression ratio and time spent once it has been fully read.
+pub fn compress(
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    encoding: ContentEncoding,
+    level: CompressionLevel,
+    key: StorageKey,
+) -> Box<dyn AsyncRead + Unpin + Send> {
+    let stats = Arc::new(CompressionStats::default());
+    let source = BufReader::new(Measured {
+        inner: reader,
+        side: Side::Source,
+        stats: stats.clone(),
+    });
+    let encoder: Box<dyn AsyncRead + Unpin + Send> = match encoding {
+        ContentEncoding::Brotli => Box::new(BrotliEncoder::with_quality(source, level.into())),
+        ContentEncoding::Zstd => Box::new(ZstdEncoder::with_quality(source, level.into())),
+        ContentEncoding::Gzip => Box::new(GzipEncoder::with_quality(source, level.into())),
+    };
+    Box::new(Logged {
+        inner: Measured {
+            inner: encoder,
+            side: Side::Encoder,
+            stats: stats.clone(),
+        },
+        stats,
+        encoding,
+        key,
+        logged: false,
+    })
+}
+
+#[derive(Default)]
+struct CompressionStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Time spent waiting on the storage backend.
+    source_nanos: AtomicU64,
+    /// Time spent in the encoder, including waiting on the storage backend.
+    total_nanos: AtomicU64,
+}
+
+/// Which side of the encoder a [`Measured`] reader sits on.
+enum Side {
+    Source,
+    Encoder,
+}
+
+/// Counts the bytes read through a reader and the time spent polling it.
+struct Measured<R> {
+    inner: R,
+    side: Side,
+    stats: Arc<CompressionStats>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Measured<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let started = Instant::now();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let (bytes, nanos) = match self.side {
+            Side::Source => (&self.stats.bytes_in, &self.stats.source_nanos),
+            Side::Encoder => (&self.stats.bytes_out, &self.stats.total_nanos),
+        };
+        nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        bytes.fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+/// Logs the compression statistics once the compressed stream reaches its end.
+struct Logged<R> {
+    inner: R,
+    stats: Arc<CompressionStats>,
+    encoding: ContentEncoding,
+    key: StorageKey,
+    logged: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Logged<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() == filled && !self.logged {
+            self.logged = true;
+            let bytes_in = self.stats.bytes_in.load(Ordering::Relaxed);
+            let bytes_out = self.stats.bytes_out.load(Ordering::Relaxed);
+            let compression_time = Duration::from_nanos(
+                self.stats
+                    .total_nanos
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(self.stats.source_nanos.load(Ordering::Relaxed)),
+            );
+            debug!(
+                key = %self.key,
+                encoding = self.encoding.as_str(),
+                bytes_in,
+                bytes_out,
+                ratio = bytes_out as f64 / bytes_in.max(1) as f64,
+                compression_time = ?compression_time,
+                "Compressed response body"
+            );
+        }
+        poll
+    }
+}
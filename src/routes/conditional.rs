@@ -0,0 +1,360 @@
+use crate::storage::FileMetadata;
+use axum::http::{HeaderMap, HeaderValue, header, response::Builder};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// All preconditions passed and the request should be served as normal.
+    Passed,
+    /// The client's cached copy is still current (304 Not Modified).
+    NotModified,
+    /// A precondition did not hold (412 Precondition Failed).
+    Failed,
+}
+
+/// Add the `ETag` and `Last-Modified` validators for a file to a response.
+pub fn with_validators(mut builder: Builder, metadata: &FileMetadata) -> Builder {
+    if let Some(etag) = metadata
+        .etag
+        .as_deref()
+        .and_then(|etag| HeaderValue::from_str(etag).ok())
+    {
+        builder = builder.header(header::ETAG, etag);
+    }
+    if let Some(last_modified) = metadata.last_modified {
+        builder = builder.header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(last_modified),
+        );
+    }
+    builder
+}
+
+/// Turn an entity tag into a weak one, for representations that are not byte-for-byte identical.
+pub fn weaken_etag(etag: Box<str>) -> Box<str> {
+    match etag.starts_with("W/") {
+        true => etag,
+        false => format!("W/{etag}").into_boxed_str(),
+    }
+}
+
+/// Evaluate the conditional request headers of a GET or HEAD request in the order
+/// described by RFC 9110 §13.2.2.
+pub fn evaluate_preconditions(headers: &HeaderMap, metadata: &FileMetadata) -> Precondition {
+    let etag = metadata.etag.as_deref();
+    let last_modified = metadata.last_modified.map(truncate_to_seconds);
+
+    if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+        if !etag_list_matches(if_match, etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = header_date(headers, header::IF_UNMODIFIED_SINCE)
+        && let Some(last_modified) = last_modified
+        && last_modified > since
+    {
+        return Precondition::Failed;
+    }
+
+    if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+        if etag_list_matches(if_none_match, etag, false) {
+            return Precondition::NotModified;
+        }
+    } else if let Some(since) = header_date(headers, header::IF_MODIFIED_SINCE)
+        && let Some(last_modified) = last_modified
+        && last_modified <= since
+    {
+        return Precondition::NotModified;
+    }
+
+    Precondition::Passed
+}
+
+/// Evaluate the conditional request headers of a request that modifies the file at a key, which
+/// may not exist yet.
+///
+/// As per RFC 9110 §13.1.2 a matching `If-None-Match` fails rather than being not modified, which
+/// allows clients to use `If-None-Match: *` to avoid overwriting an existing file. `If-Modified-Since`
+/// only applies to GET and HEAD requests and is ignored.
+pub fn evaluate_write_preconditions(
+    headers: &HeaderMap,
+    metadata: Option<&FileMetadata>,
+) -> Precondition {
+    match metadata {
+        Some(metadata) => match evaluate_preconditions(headers, metadata) {
+            Precondition::NotModified if !headers.contains_key(header::IF_NONE_MATCH) => {
+                Precondition::Passed
+            }
+            Precondition::Passed => Precondition::Passed,
+            Precondition::NotModified | Precondition::Failed => Precondition::Failed,
+        },
+        None if headers.contains_key(header::IF_MATCH) => Precondition::Failed,
+        None => Precondition::Passed,
+    }
+}
+
+/// Check whether a `Range` request should be honoured given its `If-Range` header, if any.
+///
+/// As per RFC 9110 §13.1.5 entity tags must match using strong comparison and dates must
+/// exactly match the file's modification time.
+pub fn if_range_matches(headers: &HeaderMap, metadata: &FileMetadata) -> bool {
+    let Some(if_range) = header_str(headers, header::IF_RANGE) else {
+        return true;
+    };
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return metadata
+            .etag
+            .as_deref()
+            .is_some_and(|etag| strong_eq(if_range, etag));
+    }
+    match (
+        httpdate::parse_http_date(if_range),
+        metadata.last_modified.map(truncate_to_seconds),
+    ) {
+        (Ok(date), Some(last_modified)) => date == last_modified,
+        _ => false,
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    header_str(headers, name).and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// HTTP dates only have second precision, so anything finer is dropped before comparing.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| UNIX_EPOCH + Duration::from_secs(duration.as_secs()))
+        .unwrap_or(time)
+}
+
+/// Match a comma-separated `If-Match`/`If-None-Match` list against an entity tag.
+fn etag_list_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    parse_etag_list(list).into_iter().any(|candidate| {
+        if strong {
+            strong_eq(candidate, etag)
+        } else {
+            opaque_tag(candidate) == opaque_tag(etag)
+        }
+    })
+}
+
+/// Split a list of entity tags, respecting commas that appear inside of quoted tags.
+fn parse_etag_list(list: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = list.trim_start_matches([' ', '\t', ',']);
+    while !rest.is_empty() {
+        let opening = if rest.starts_with("W/\"") { 3 } else { 1 };
+        if !rest[opening - 1..].starts_with('"') {
+            break;
+        }
+        let Some(closing) = rest[opening..].find('"') else {
+            break;
+        };
+        let end = opening + closing + 1;
+        tags.push(&rest[..end]);
+        rest = rest[end..].trim_start_matches([' ', '\t', ',']);
+    }
+    tags
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    etag.trim().trim_start_matches("W/")
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim(), b.trim());
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+    const BEFORE: &str = "Tue, 20 Oct 2015 07:28:00 GMT";
+
+    fn metadata(etag: &str) -> FileMetadata {
+        FileMetadata {
+            file_size: 10,
+            is_dir: false,
+            // Sub-second precision is lost in HTTP dates and must not fail comparisons.
+            last_modified: Some(
+                httpdate::parse_http_date(MODIFIED).unwrap() + Duration::from_millis(500),
+            ),
+            etag: Some(etag.into()),
+        }
+    }
+
+    fn headers(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn without_conditions_requests_pass() {
+        assert_eq!(
+            evaluate_preconditions(&HeaderMap::new(), &metadata("\"a\"")),
+            Precondition::Passed
+        );
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let metadata = metadata("W/\"a\"");
+        for value in ["\"a\"", "W/\"a\"", "\"b\", W/\"a\"", "*"] {
+            assert_eq!(
+                evaluate_preconditions(&headers(&[(header::IF_NONE_MATCH, value)]), &metadata),
+                Precondition::NotModified,
+                "{value:?}"
+            );
+        }
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_NONE_MATCH, "\"b\"")]), &metadata),
+            Precondition::Passed
+        );
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let strong = metadata("\"a\"");
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MATCH, "\"b\", \"a\"")]), &strong),
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MATCH, "\"b\"")]), &strong),
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MATCH, "W/\"a\"")]), &strong),
+            Precondition::Failed
+        );
+        let weak = metadata("W/\"a\"");
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MATCH, "W/\"a\"")]), &weak),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn tags_may_contain_commas() {
+        let metadata = metadata("\"a,b\"");
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MATCH, "\"x\", \"a,b\"")]), &metadata),
+            Precondition::Passed
+        );
+    }
+
+    #[test]
+    fn dates_are_compared_to_the_second() {
+        let metadata = metadata("\"a\"");
+        assert_eq!(
+            evaluate_preconditions(
+                &headers(&[(header::IF_MODIFIED_SINCE, MODIFIED)]),
+                &metadata
+            ),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate_preconditions(&headers(&[(header::IF_MODIFIED_SINCE, BEFORE)]), &metadata),
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                &headers(&[(header::IF_UNMODIFIED_SINCE, MODIFIED)]),
+                &metadata
+            ),
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                &headers(&[(header::IF_UNMODIFIED_SINCE, BEFORE)]),
+                &metadata
+            ),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn tags_take_precedence_over_dates() {
+        let metadata = metadata("\"a\"");
+        // A matching tag overrides a date the file was modified since, and vice versa.
+        let not_modified = headers(&[
+            (header::IF_NONE_MATCH, "\"a\""),
+            (header::IF_MODIFIED_SINCE, BEFORE),
+        ]);
+        assert_eq!(
+            evaluate_preconditions(&not_modified, &metadata),
+            Precondition::NotModified
+        );
+        let passed = headers(&[
+            (header::IF_MATCH, "\"a\""),
+            (header::IF_UNMODIFIED_SINCE, BEFORE),
+        ]);
+        assert_eq!(
+            evaluate_preconditions(&passed, &metadata),
+            Precondition::Passed
+        );
+    }
+
+    #[test]
+    fn write_preconditions() {
+        let metadata = metadata("\"a\"");
+        let create_only = headers(&[(header::IF_NONE_MATCH, "*")]);
+        assert_eq!(
+            evaluate_write_preconditions(&create_only, Some(&metadata)),
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluate_write_preconditions(&create_only, None),
+            Precondition::Passed
+        );
+        let if_match = headers(&[(header::IF_MATCH, "\"a\"")]);
+        assert_eq!(
+            evaluate_write_preconditions(&if_match, None),
+            Precondition::Failed
+        );
+        let modified_since = headers(&[(header::IF_MODIFIED_SINCE, MODIFIED)]);
+        assert_eq!(
+            evaluate_write_preconditions(&modified_since, Some(&metadata)),
+            Precondition::Passed
+        );
+    }
+
+    #[test]
+    fn if_range() {
+        let metadata = metadata("\"a\"");
+        for (value, matches) in [
+            ("\"a\"", true),
+            ("\"b\"", false),
+            ("W/\"a\"", false),
+            (MODIFIED, true),
+            (BEFORE, false),
+        ] {
+            assert_eq!(
+                if_range_matches(&headers(&[(header::IF_RANGE, value)]), &metadata),
+                matches,
+                "{value:?}"
+            );
+        }
+        assert!(if_range_matches(&HeaderMap::new(), &metadata));
+    }
+
+    #[test]
+    fn weakened_tags() {
+        assert_eq!(&*weaken_etag("\"a\"".into()), "W/\"a\"");
+        assert_eq!(&*weaken_etag("W/\"a\"".into()), "W/\"a\"");
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{FileMetadata, StorageError, StorageKey, StorageOperations, StorageResult},
+};
+use axum::http::{HeaderMap, header};
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ContentEncoding {
+    #[value(name = "br")]
+    Brotli,
+    #[value(name = "zstd")]
+    Zstd,
+    #[value(name = "gzip")]
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// The token used for this encoding in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    /// The file extension used for precompressed siblings in this encoding.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zst",
+            ContentEncoding::Gzip => "gz",
+        }
+    }
+}
+
+/// A precompressed sibling of a requested file that can be served in its place.
+pub struct Precompressed {
+    pub encoding: ContentEncoding,
+    pub key: StorageKey,
+    pub metadata: FileMetadata,
+}
+
+/// Check whether the client's `Accept-Encoding` header allows the given encoding.
+///
+/// Quality values are only used to exclude encodings (`q=0`), the server's configured order
+/// of preference decides between acceptable encodings.
+pub fn accepts_encoding(headers: &HeaderMap, encoding: ContentEncoding) -> bool {
+    let mut wildcard = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if coding.eq_ignore_ascii_case(encoding.as_str())
+                || (encoding == ContentEncoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+            {
+                return quality > 0.0;
+            }
+            if coding == "*" {
+                wildcard = Some(quality > 0.0);
+            }
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+/// Find the most preferred precompressed sibling of `key` (e.g. `app.js.br`) that the client accepts.
+pub async fn find_precompressed(
+    key: &StorageKey,
+    headers: &HeaderMap,
+    mount: &Mount,
+    state: &AppState,
+) -> StorageResult<Option<Precompressed>> {
+    for &encoding in state.precompressed.iter() {
+        if !accepts_encoding(headers, encoding) {
+            continue;
+        }
+        let sibling = StorageKey::new(&format!("{key}.{}", encoding.extension()))?;
+        match mount.storage.file_metadata(&sibling).await {
+            Ok(metadata) => {
+                return Ok(Some(Precompressed {
+                    encoding,
+                    key: sibling,
+                    metadata,
+                }));
+            }
+            Err(StorageError::NotFound | StorageError::Forbidden(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,66 @@
+use crate::{request_context::X_REQUEST_ID, storage::StorageError};
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
+    response::IntoResponse,
+};
+use tracing::{error, warn};
+
+/// Turn a storage error into an RFC 9457 `application/problem+json` response.
+///
+/// Details of server-side failures are only logged and never sent to the client.
+pub fn error_response(err: StorageError, headers: &HeaderMap) -> Response<Body> {
+    let (status, detail) = match &err {
+        StorageError::NotFound => (StatusCode::NOT_FOUND, None),
+        StorageError::InvalidPath(_) => (StatusCode::BAD_REQUEST, Some(err.to_string())),
+        StorageError::Forbidden(_) => {
+            warn!("Denied access to storage: {err}");
+            (StatusCode::FORBIDDEN, None)
+        }
+        StorageError::Unavailable(_) => {
+            error!("{err}");
+            (StatusCode::SERVICE_UNAVAILABLE, None)
+        }
+        StorageError::Timeout(_) => {
+            error!("{err}");
+            (StatusCode::GATEWAY_TIMEOUT, None)
+        }
+        StorageError::Backend(_) => {
+            error!("{err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        }
+    };
+    problem_response(status, detail, headers)
+}
+
+/// Build an `application/problem+json` response, tagged with the ID of the request it answers.
+pub fn problem_response(
+    status: StatusCode,
+    detail: Option<String>,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let request_id = headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok());
+
+    let mut body = serde_json::json!({
+        "type": "about:blank",
+        "title": status.canonical_reason(),
+        "status": status.as_u16(),
+    });
+    if let Some(detail) = detail {
+        body["detail"] = detail.into();
+    }
+    if let Some(request_id) = request_id {
+        body["request_id"] = request_id.into();
+    }
+    Response::builder()
+        .status(status)
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        )
+        .body(Body::from(body.to_string()))
+        .unwrap()
+        .into_response()
+}
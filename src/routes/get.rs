@@ -1,57 +1,229 @@
-use crate::{AppState, storage::StorageOperations};
+use super::autoindex::directory_listing;
+use super::compression::compress;
+use super::error::error_response;
+use super::range::{RangeRequest, content_range, unsatisfied_content_range};
+use super::representation::{Representation, Selected, select_representation};
+use super::resolve::{Resolved, directory_redirect, resolve_request};
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{FileMetadata, StorageBackend, StorageError, StorageKey, StorageOperations},
+};
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{Path, State},
-    http::{HeaderValue, Response, StatusCode, header},
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use futures_util::{StreamExt, TryStreamExt, stream};
+use std::{
+    future::ready,
+    io,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use mime_guess::{MimeGuess, mime};
-use std::path::PathBuf;
 use tokio_util::io::ReaderStream;
+use tracing::error;
 
-pub async fn get_file_root_handler(State(state): State<AppState>) -> impl IntoResponse {
-    serve_file(PathBuf::from("index.html"), state).await
+pub async fn get_file_root_handler(
+    uri: Uri,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    serve_path("", &uri, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
 }
 
 pub async fn get_file_handler(
-    Path(mut path): Path<PathBuf>,
+    Path(path): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    if path.to_string_lossy().ends_with('/') {
-        path.push("index.html");
+    serve_path(&path, &uri, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
+}
+
+async fn serve_path(
+    path: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let (mount, resolved) = resolve_request(path, &state).await?;
+    match resolved {
+        Resolved::File(key, metadata) => serve_file(key, metadata, headers, mount, state).await,
+        Resolved::Redirect => Ok(directory_redirect(uri)),
+        Resolved::Directory(key) if state.autoindex => {
+            directory_listing(key.as_ref(), uri, headers, &mount, &state).await
+        }
+        Resolved::Directory(_) => Err(StorageError::NotFound),
     }
-    serve_file(path, state).await
 }
 
-async fn serve_file(path: PathBuf, state: AppState) -> impl IntoResponse {
-    let Some(reader) = state.storage.read_stream(&path).await.unwrap() else {
-        return StatusCode::NOT_FOUND.into_response();
+async fn serve_file(
+    key: StorageKey,
+    metadata: FileMetadata,
+    headers: &HeaderMap,
+    mount: Mount,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let Representation {
+        key,
+        metadata,
+        content_type,
+        dynamic_encoding,
+        range_request,
+        mut response_builder,
+    } = match select_representation(key, metadata, headers, true, &mount, &state).await? {
+        Selected::Representation(representation) => representation,
+        Selected::Answered(response) => return Ok(response),
     };
 
-    let content_type = MimeGuess::from_path(&path)
-        .first_raw()
-        .map(HeaderValue::from_static)
-        .unwrap_or_else(|| HeaderValue::from_str(mime::APPLICATION_OCTET_STREAM.as_ref()).unwrap());
-    let mut response_builder = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type);
-
-    if let Some(cache_duration) = state.file_cache_duration {
-        response_builder = response_builder.header(
-            header::CACHE_CONTROL,
-            HeaderValue::from_str(&format!(
-                "public, max-age={}, immutable",
-                cache_duration.as_secs()
+    let response = match range_request {
+        RangeRequest::Full => {
+            let mut reader = mount.storage.read_stream(&key).await?;
+            response_builder = response_builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, content_type);
+            match dynamic_encoding {
+                Some(encoding) => {
+                    reader = compress(reader, encoding, state.compression_level, key.clone());
+                }
+                None => {
+                    response_builder =
+                        response_builder.header(header::CONTENT_LENGTH, metadata.file_size);
+                }
+            }
+            response_builder.body(stream_body(
+                key,
+                ReaderStream::with_capacity(reader, mount.file_stream_buffersize),
             ))
-            .unwrap(),
-        );
-    }
+        }
+        RangeRequest::Unsatisfiable => response_builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(
+                header::CONTENT_RANGE,
+                unsatisfied_content_range(metadata.file_size),
+            )
+            .body(Body::empty()),
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges.into_iter().next().unwrap();
+            let reader = mount.storage.read_range(&key, range.clone()).await?;
+            response_builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, range.end - range.start)
+                .header(
+                    header::CONTENT_RANGE,
+                    content_range(&range, metadata.file_size),
+                )
+                .body(stream_body(
+                    key,
+                    ReaderStream::with_capacity(reader, mount.file_stream_buffersize),
+                ))
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = multipart_boundary();
+            let (content_length, body) = multipart_body(
+                mount.storage,
+                key,
+                ranges,
+                content_type.to_str().unwrap_or_default(),
+                metadata.file_size,
+                &boundary,
+                mount.file_stream_buffersize,
+            );
+            response_builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={boundary}"),
+                )
+                .header(header::CONTENT_LENGTH, content_length)
+                .body(body)
+        }
+    };
+    Ok(response.unwrap().into_response())
+}
+
+/// Wrap a file stream into a response body.
+///
+/// By the time the body is being streamed the status and headers have already been sent,
+/// so any failures can no longer be reported to the client and are logged instead.
+fn stream_body<S>(key: StorageKey, stream: S) -> Body
+where
+    S: futures_util::Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    Body::from_stream(stream.inspect_err(move |err| {
+        error!("Failed to stream {key} after response headers were sent: {err}")
+    }))
+}
+
+/// Generate a boundary for a `multipart/byteranges` body that is unique to this response.
+fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "hermes-{nanos:x}-{:x}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Build a lazily-read `multipart/byteranges` body, returning it alongside its exact length.
+///
+/// Each part is only opened once the previous part has been fully streamed to the client.
+fn multipart_body(
+    storage: StorageBackend,
+    key: StorageKey,
+    ranges: Vec<Range<usize>>,
+    content_type: &str,
+    file_size: usize,
+    boundary: &str,
+    buffer_size: usize,
+) -> (usize, Body) {
+    let parts = ranges
+        .into_iter()
+        .map(|range| {
+            let head = format!(
+                "\r\n--{boundary}\r\n{}: {content_type}\r\n{}: {}\r\n\r\n",
+                header::CONTENT_TYPE,
+                header::CONTENT_RANGE,
+                content_range(&range, file_size)
+            );
+            (Bytes::from(head), range)
+        })
+        .collect::<Vec<_>>();
+    let tail = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let content_length = parts
+        .iter()
+        .map(|(head, range)| head.len() + range.end - range.start)
+        .sum::<usize>()
+        + tail.len();
+
+    let body = stream::iter(parts)
+        .flat_map({
+            let key = key.clone();
+            move |(head, range)| {
+                let storage = storage.clone();
+                let key = key.clone();
+                let part = stream::once(async move { storage.read_range(&key, range).await })
+                    .map(move |result| {
+                        result
+                            .map(|reader| ReaderStream::with_capacity(reader, buffer_size))
+                            .map_err(io::Error::other)
+                    })
+                    .try_flatten();
+                stream::once(ready(Ok(head))).chain(part)
+            }
+        })
+        .chain(stream::once(ready(Ok(tail))));
 
-    response_builder
-        .body(Body::from_stream(ReaderStream::with_capacity(
-            reader,
-            state.file_stream_buffersize,
-        )))
-        .unwrap()
-        .into_response()
+    (content_length, stream_body(key, body))
 }
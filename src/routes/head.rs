@@ -1,54 +1,90 @@
-use crate::{AppState, storage::StorageOperations};
+use super::autoindex::directory_listing;
+use super::error::error_response;
+use super::representation::{Representation, Selected, select_representation};
+use super::resolve::{Resolved, directory_redirect, resolve_request};
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{FileMetadata, StorageError, StorageKey},
+};
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{Path, State},
-    http::{HeaderValue, Response, StatusCode, header},
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Response as AxumResponse},
 };
-use mime_guess::{MimeGuess, mime};
-use std::path::PathBuf;
+use futures_util::stream;
+use std::io;
 
-pub async fn head_file_root_handler(State(state): State<AppState>) -> impl IntoResponse {
-    file_metadata(PathBuf::from("index.html"), state).await
+pub async fn head_file_root_handler(
+    uri: Uri,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    path_metadata("", &uri, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
 }
 
 pub async fn head_file_handler(
-    Path(mut path): Path<PathBuf>,
+    Path(path): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    if path.to_string_lossy().ends_with('/') {
-        path.push("index.html");
+    path_metadata(&path, &uri, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
+}
+
+async fn path_metadata(
+    path: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let (mount, resolved) = resolve_request(path, &state).await?;
+    match resolved {
+        Resolved::File(key, metadata) => file_metadata(key, metadata, headers, mount, state).await,
+        Resolved::Redirect => Ok(directory_redirect(uri)),
+        Resolved::Directory(key) if state.autoindex => {
+            directory_listing(key.as_ref(), uri, headers, &mount, &state).await
+        }
+        Resolved::Directory(_) => Err(StorageError::NotFound),
     }
-    file_metadata(path, state).await
 }
 
-async fn file_metadata(path: PathBuf, state: AppState) -> impl IntoResponse {
-    let Some(metadata) = state.storage.metadata(&path).await.unwrap() else {
-        return StatusCode::NOT_FOUND.into_response();
+async fn file_metadata(
+    key: StorageKey,
+    metadata: FileMetadata,
+    headers: &HeaderMap,
+    mount: Mount,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let Representation {
+        metadata,
+        content_type,
+        dynamic_encoding,
+        mut response_builder,
+        ..
+    } = match select_representation(key, metadata, headers, false, &mount, &state).await? {
+        Selected::Representation(representation) => representation,
+        Selected::Answered(response) => return Ok(response),
     };
 
-    let content_type = MimeGuess::from_path(&path)
-        .first_raw()
-        .map(HeaderValue::from_static)
-        .unwrap_or_else(|| HeaderValue::from_str(mime::APPLICATION_OCTET_STREAM.as_ref()).unwrap());
-    let mut response_builder = Response::builder()
+    // The compressed length isn't known without compressing the file, so the body is given an
+    // unknown size to stop an empty body from advertising a `Content-Length` of zero.
+    let body = match dynamic_encoding {
+        Some(_) => Body::from_stream(stream::empty::<io::Result<Bytes>>()),
+        None => {
+            response_builder = response_builder.header(header::CONTENT_LENGTH, metadata.file_size);
+            Body::empty()
+        }
+    };
+    Ok(response_builder
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type);
-
-    if let Some(cache_duration) = state.file_cache_duration {
-        response_builder = response_builder.header(
-            header::CACHE_CONTROL,
-            HeaderValue::from_str(&format!(
-                "public, max-age={}, immutable",
-                cache_duration.as_secs()
-            ))
-            .unwrap(),
-        );
-    }
-
-    response_builder
-        .header(header::CONTENT_LENGTH, metadata.file_size)
-        .body(Body::empty())
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
         .unwrap()
-        .into_response()
+        .into_response())
 }
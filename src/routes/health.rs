@@ -0,0 +1,62 @@
+use crate::{
+    AppState,
+    routes::problem_response,
+    storage::{StorageBackend, StorageOperations},
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::join_all;
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+
+/// How long the storage backend has to respond to a readiness check.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness check that succeeds for as long as the server is able to respond.
+pub async fn healthz_handler() -> Response {
+    (StatusCode::OK, "OK").into_response()
+}
+
+/// Readiness check that succeeds when the storage backends mounted by the site are able to serve
+/// requests.
+pub async fn readyz_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let storages = state.mounts.storages().cloned().collect::<Vec<_>>();
+    readiness(&storages, &headers).await
+}
+
+/// Readiness check that succeeds when the storage backends of every site are able to serve requests.
+pub async fn readyz_all_handler(
+    State(storages): State<Arc<[StorageBackend]>>,
+    headers: HeaderMap,
+) -> Response {
+    readiness(&storages, &headers).await
+}
+
+async fn readiness(storages: &[StorageBackend], headers: &HeaderMap) -> Response {
+    let checks = storages.iter().map(|storage| async move {
+        match tokio::time::timeout(READINESS_TIMEOUT, storage.health_check()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(err)) => {
+                warn!(
+                    "Readiness check of {} storage failed: {err}",
+                    storage.name()
+                );
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Readiness check of {} storage timed out after {READINESS_TIMEOUT:?}",
+                    storage.name()
+                );
+                false
+            }
+        }
+    });
+    match join_all(checks).await.into_iter().all(|ready| ready) {
+        true => (StatusCode::OK, "OK").into_response(),
+        false => problem_response(StatusCode::SERVICE_UNAVAILABLE, None, headers),
+    }
+}
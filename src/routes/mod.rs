@@ -2,3 +2,19 @@ mod get;
 pub use get::*;
 mod head;
 pub use head::*;
+mod health;
+pub use health::*;
+mod write;
+pub use write::*;
+mod autoindex;
+pub use autoindex::encode_path;
+mod compression;
+pub use compression::CompressionLevel;
+mod conditional;
+mod encoding;
+pub use encoding::ContentEncoding;
+mod error;
+pub use error::problem_response;
+mod range;
+mod representation;
+mod resolve;
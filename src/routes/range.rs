@@ -0,0 +1,161 @@
+use std::ops::Range;
+
+/// The maximum number of ranges that will be honoured in a single request before the
+/// `Range` header is ignored and the full file is served instead.
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The header was absent, malformed or unsupported; serve the full file.
+    Full,
+    /// One or more satisfiable, sorted and non-overlapping byte ranges.
+    Partial(Vec<Range<usize>>),
+    /// None of the requested ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header value against a file of `size` bytes as per RFC 9110 §14.
+///
+/// Overlapping or adjacent ranges are coalesced to avoid serving the same bytes twice.
+pub fn parse_range_header(value: &str, size: usize) -> RangeRequest {
+    let Some((unit, specs)) = value.trim().split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let specs = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let Some((start, end)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<usize>() else {
+                    return RangeRequest::Full;
+                };
+                size.saturating_sub(suffix)..size
+            }
+            (start, "") => {
+                let Ok(start) = start.parse::<usize>() else {
+                    return RangeRequest::Full;
+                };
+                start..size
+            }
+            (start, end) => {
+                let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) else {
+                    return RangeRequest::Full;
+                };
+                if end < start {
+                    return RangeRequest::Full;
+                }
+                start..end.saturating_add(1).min(size)
+            }
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    RangeRequest::Partial(coalesced)
+}
+
+/// Format a `Content-Range` header value for a satisfied range.
+pub fn content_range(range: &Range<usize>, size: usize) -> String {
+    format!("bytes {}-{}/{size}", range.start, range.end - 1)
+}
+
+/// Format a `Content-Range` header value for an unsatisfiable range response.
+pub fn unsatisfied_content_range(size: usize) -> String {
+    format!("bytes */{size}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RangeRequest::{Full, Unsatisfiable};
+
+    fn single(range: Range<usize>) -> RangeRequest {
+        RangeRequest::Partial(vec![range])
+    }
+
+    fn partial(ranges: &[Range<usize>]) -> RangeRequest {
+        RangeRequest::Partial(ranges.to_vec())
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range_header("bytes=0-9", 100), single(0..10));
+        assert_eq!(parse_range_header("bytes=90-", 100), single(90..100));
+        assert_eq!(parse_range_header("bytes=-10", 100), single(90..100));
+        // Ranges past the end of the file are truncated to it.
+        assert_eq!(parse_range_header("bytes=50-500", 100), single(50..100));
+        assert_eq!(parse_range_header("bytes=-500", 100), single(0..100));
+    }
+
+    #[test]
+    fn multiple_ranges_are_sorted_and_coalesced() {
+        assert_eq!(
+            parse_range_header("bytes=50-59, 0-9", 100),
+            partial(&[0..10, 50..60])
+        );
+        assert_eq!(
+            parse_range_header("bytes=0-9,5-19,20-29", 100),
+            single(0..30)
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range_header("bytes=100-", 100), Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=200-300", 100), Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=-0", 100), Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=0-0", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn invalid_headers_serve_the_full_file() {
+        for value in [
+            "",
+            "bytes",
+            "bytes=",
+            "items=0-9",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=0-9,x",
+            "bytes=--1",
+        ] {
+            assert_eq!(parse_range_header(value, 100), Full, "{value:?}");
+        }
+        let too_many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse_range_header(&format!("bytes={too_many}"), 100), Full);
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(content_range(&(0..10), 100), "bytes 0-9/100");
+        assert_eq!(unsatisfied_content_range(100), "bytes */100");
+    }
+}
@@ -0,0 +1,149 @@
+use super::compression::select_dynamic_encoding;
+use super::conditional::{
+    Precondition, evaluate_preconditions, if_range_matches, weaken_etag, with_validators,
+};
+use super::encoding::{ContentEncoding, find_precompressed};
+use super::error::problem_response;
+use super::range::{RangeRequest, parse_range_header};
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{FileMetadata, StorageKey, StorageResult},
+};
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header, response::Builder},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use mime_guess::{MimeGuess, mime};
+
+/// The representation of a file that a `GET` or `HEAD` request is answered with.
+pub struct Representation {
+    /// The file to read, a precompressed sibling of the requested file if one was chosen.
+    pub key: StorageKey,
+    pub metadata: FileMetadata,
+    pub content_type: HeaderValue,
+    /// The encoding to compress the file with on-the-fly, if any.
+    pub dynamic_encoding: Option<ContentEncoding>,
+    /// The parts of the file requested, always all of it when compressing on-the-fly.
+    pub range_request: RangeRequest,
+    /// A response carrying the headers shared by every answer, such as validators and encoding.
+    pub response_builder: Builder,
+}
+
+/// How a `GET` or `HEAD` request for a file is answered.
+pub enum Selected {
+    Representation(Representation),
+    /// The request's preconditions decided the response without the file's contents.
+    Answered(AxumResponse),
+}
+
+/// Choose the representation of a file to answer a request with and evaluate the request's
+/// preconditions against it. The `Range` header is only honoured when `ranges` is set, as range
+/// requests are only defined for `GET`.
+pub async fn select_representation(
+    key: StorageKey,
+    metadata: FileMetadata,
+    headers: &HeaderMap,
+    ranges: bool,
+    mount: &Mount,
+    state: &AppState,
+) -> StorageResult<Selected> {
+    let content_type = MimeGuess::from_path(key.as_path())
+        .first_raw()
+        .map(HeaderValue::from_static)
+        .unwrap_or_else(|| HeaderValue::from_str(mime::APPLICATION_OCTET_STREAM.as_ref()).unwrap());
+    let precompressed = find_precompressed(&key, headers, mount, state).await?;
+    let mut response_builder =
+        Response::builder().header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if !state.precompressed.is_empty() || !state.compression.is_empty() {
+        response_builder =
+            response_builder.header(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    let (key, mut metadata, dynamic_encoding, range_request) = match precompressed {
+        Some(precompressed) => {
+            response_builder = response_builder.header(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(precompressed.encoding.as_str()),
+            );
+            let range_request = range_request(headers, ranges, &precompressed.metadata);
+            (
+                precompressed.key,
+                precompressed.metadata,
+                None,
+                range_request,
+            )
+        }
+        None => {
+            let range_request = range_request(headers, ranges, &metadata);
+            let encoding = select_dynamic_encoding(
+                headers,
+                content_type.to_str().unwrap_or_default(),
+                metadata.file_size,
+                matches!(range_request, RangeRequest::Partial(_)),
+                state,
+            );
+            match encoding {
+                Some(encoding) => {
+                    response_builder = response_builder.header(
+                        header::CONTENT_ENCODING,
+                        HeaderValue::from_static(encoding.as_str()),
+                    );
+                    (key, metadata, Some(encoding), RangeRequest::Full)
+                }
+                None => (key, metadata, None, range_request),
+            }
+        }
+    };
+    // Compressing on-the-fly changes the bytes but not the meaning of the file, so the tag is
+    // weakened to still allow revalidation while never satisfying a strong comparison.
+    if dynamic_encoding.is_some() {
+        metadata.etag = metadata.etag.map(weaken_etag);
+    }
+    let mut response_builder = with_validators(response_builder, &metadata);
+
+    if let Some(cache_duration) = mount.file_cache_duration {
+        response_builder = response_builder.header(
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&format!(
+                "public, max-age={}, immutable",
+                cache_duration.as_secs()
+            ))
+            .unwrap(),
+        );
+    }
+
+    match evaluate_preconditions(headers, &metadata) {
+        Precondition::Passed => Ok(Selected::Representation(Representation {
+            key,
+            metadata,
+            content_type,
+            dynamic_encoding,
+            range_request,
+            response_builder,
+        })),
+        Precondition::NotModified => Ok(Selected::Answered(
+            response_builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap()
+                .into_response(),
+        )),
+        Precondition::Failed => Ok(Selected::Answered(problem_response(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            headers,
+        ))),
+    }
+}
+
+/// The parts of a file a request asks for, the whole file unless the request has a `Range` header
+/// that is honoured and still applies to the file as per its `If-Range` header.
+fn range_request(headers: &HeaderMap, ranges: bool, metadata: &FileMetadata) -> RangeRequest {
+    match headers.get(header::RANGE).map(HeaderValue::to_str) {
+        Some(Ok(value)) if ranges && if_range_matches(headers, metadata) => {
+            parse_range_header(value, metadata.file_size)
+        }
+        _ => RangeRequest::Full,
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    AppState,
+    mounts::Mount,
+    storage::{FileMetadata, StorageError, StorageKey, StorageOperations, StorageResult},
+};
+use axum::{
+    http::{HeaderValue, StatusCode, Uri, header},
+    response::{IntoResponse, Response as AxumResponse},
+};
+
+/// What a request path refers to once mounts, directories and index files have been resolved.
+pub enum Resolved {
+    /// A file that should be served.
+    File(StorageKey, FileMetadata),
+    /// A directory with no index file, `None` being the mount's root.
+    Directory(Option<StorageKey>),
+    /// A directory that was requested without a trailing slash and should be redirected.
+    Redirect,
+}
+
+/// Resolve a request path to the mount serving it and a file within it, falling back to the
+/// configured index files when it refers to a directory.
+///
+/// A trailing slash on a file is treated as not found, as it can never be a valid file path.
+pub async fn resolve_request(path: &str, state: &AppState) -> StorageResult<(Mount, Resolved)> {
+    let (mount, mount_path) = state.mounts.resolve(path).ok_or(StorageError::NotFound)?;
+    let mount = mount.clone();
+    // The root of a mount is a directory like any other.
+    if mount_path.is_empty()
+        && !mount.is_root()
+        && !path.ends_with('/')
+        && state.redirect_directories
+    {
+        return Ok((mount, Resolved::Redirect));
+    }
+    let has_trailing_slash = mount_path.is_empty() || mount_path.ends_with('/');
+    let key = match mount_path.trim_end_matches('/') {
+        "" => None,
+        path => Some(StorageKey::new(path)?),
+    };
+
+    if let Some(key) = &key {
+        let metadata = mount.storage.metadata(key).await?;
+        if !metadata.is_dir {
+            return match has_trailing_slash {
+                true => Err(StorageError::NotFound),
+                false => Ok((mount, Resolved::File(key.clone(), metadata))),
+            };
+        }
+        if !has_trailing_slash && state.redirect_directories {
+            return Ok((mount, Resolved::Redirect));
+        }
+    }
+
+    for index_file in state.index_files.iter() {
+        let index_key = match &key {
+            Some(key) => StorageKey::new(&format!("{key}/{index_file}"))?,
+            None => StorageKey::new(index_file)?,
+        };
+        match mount.storage.file_metadata(&index_key).await {
+            Ok(metadata) => return Ok((mount, Resolved::File(index_key, metadata))),
+            Err(StorageError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((mount, Resolved::Directory(key)))
+}
+
+/// Permanently redirect a directory to its canonical trailing-slash form, preserving the query.
+pub fn directory_redirect(uri: &Uri) -> AxumResponse {
+    let location = match uri.query() {
+        Some(query) => format!("{}/?{query}", uri.path()),
+        None => format!("{}/", uri.path()),
+    };
+    match HeaderValue::from_str(&location) {
+        Ok(location) => (
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, location)],
+        )
+            .into_response(),
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
@@ -0,0 +1,206 @@
+use super::conditional::{Precondition, evaluate_write_preconditions, with_validators};
+use super::error::{error_response, problem_response};
+use crate::{
+    AppState,
+    storage::{
+        FileMetadata, StorageBackend, StorageError, StorageKey, StorageOperations, StorageResult,
+    },
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, Method, Response, StatusCode, Uri, header},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use futures_util::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use std::io;
+use tokio_util::io::StreamReader;
+
+pub async fn put_file_handler(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Body,
+) -> impl IntoResponse {
+    put_file(&path, &headers, body, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
+}
+
+pub async fn delete_file_handler(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    delete_file(&path, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
+}
+
+/// Handles the WebDAV `MOVE` method, which axum can't route by name, so this is mounted as the
+/// fallback for all other methods.
+pub async fn move_file_handler(
+    method: Method,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if method.as_str() != "MOVE" {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(
+                header::ALLOW,
+                HeaderValue::from_static("GET,HEAD,PUT,DELETE,MOVE"),
+            )],
+        )
+            .into_response();
+    }
+    move_file(&path, &headers, state)
+        .await
+        .unwrap_or_else(|err| error_response(err, &headers))
+}
+
+async fn put_file(
+    path: &str,
+    headers: &HeaderMap,
+    body: Body,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let (mount, path) = state.mounts.resolve(path).ok_or(StorageError::NotFound)?;
+    let key = file_key(&path)?;
+    let existing = existing_file(&key, &mount.storage).await?;
+    if evaluate_write_preconditions(headers, existing.as_ref()) == Precondition::Failed {
+        return Ok(problem_response(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            headers,
+        ));
+    }
+
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    mount.storage.write_stream(&key, Box::new(reader)).await?;
+    let metadata = mount.storage.metadata(&key).await?;
+    Ok(with_validators(Response::builder(), &metadata)
+        .status(match existing {
+            Some(_) => StatusCode::NO_CONTENT,
+            None => StatusCode::CREATED,
+        })
+        .body(Body::empty())
+        .unwrap()
+        .into_response())
+}
+
+async fn delete_file(
+    path: &str,
+    headers: &HeaderMap,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let (mount, path) = state.mounts.resolve(path).ok_or(StorageError::NotFound)?;
+    let key = file_key(&path)?;
+    let existing = existing_file(&key, &mount.storage)
+        .await?
+        .ok_or(StorageError::NotFound)?;
+    if evaluate_write_preconditions(headers, Some(&existing)) == Precondition::Failed {
+        return Ok(problem_response(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            headers,
+        ));
+    }
+    mount.storage.delete(&key).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn move_file(
+    path: &str,
+    headers: &HeaderMap,
+    state: AppState,
+) -> Result<AxumResponse, StorageError> {
+    let (mount, path) = state.mounts.resolve(path).ok_or(StorageError::NotFound)?;
+    let key = file_key(&path)?;
+    let destination_path = destination_path(headers)?;
+    let (destination_mount, destination_path) = state
+        .mounts
+        .resolve(&destination_path)
+        .ok_or(StorageError::NotFound)?;
+    // Files can only be renamed within a storage backend.
+    if !mount.is_same(destination_mount) {
+        return Err(StorageError::InvalidPath(
+            "Destination must be on the same mount as the source".into(),
+        ));
+    }
+    let destination = file_key(&destination_path)?;
+    if key == destination {
+        return Err(StorageError::InvalidPath(
+            "Destination must differ from the source".into(),
+        ));
+    }
+    let existing = existing_file(&key, &mount.storage)
+        .await?
+        .ok_or(StorageError::NotFound)?;
+    if evaluate_write_preconditions(headers, Some(&existing)) == Precondition::Failed {
+        return Ok(problem_response(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            headers,
+        ));
+    }
+    let replaced = existing_file(&destination, &mount.storage).await?.is_some();
+    // As per RFC 4918 §10.6 existing destinations are replaced unless `Overwrite: F` is sent.
+    if replaced
+        && headers
+            .get("overwrite")
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"F"))
+    {
+        return Ok(problem_response(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            headers,
+        ));
+    }
+    mount.storage.rename(&key, &destination).await?;
+    Ok(match replaced {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    }
+    .into_response())
+}
+
+/// Parse a request path into the key of a file, rejecting paths that refer to a directory.
+fn file_key(path: &str) -> StorageResult<StorageKey> {
+    if path.is_empty() || path.ends_with('/') {
+        return Err(StorageError::InvalidPath(
+            format!("Path does not refer to a file: {path:?}").into(),
+        ));
+    }
+    StorageKey::new(path)
+}
+
+/// Parse the `Destination` header of a `MOVE` request, either an absolute URI or path.
+pub fn destination_path(headers: &HeaderMap) -> StorageResult<String> {
+    let destination = headers
+        .get("destination")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Uri>().ok())
+        .ok_or_else(|| StorageError::InvalidPath("Missing or invalid Destination header".into()))?;
+    let path = percent_decode_str(destination.path().trim_start_matches('/'))
+        .decode_utf8()
+        .map_err(|_| StorageError::InvalidPath("Destination is not valid UTF-8".into()))?;
+    Ok(path.into_owned())
+}
+
+/// Fetch the metadata of the file at `key`, if it exists.
+async fn existing_file(
+    key: &StorageKey,
+    storage: &StorageBackend,
+) -> StorageResult<Option<FileMetadata>> {
+    match storage.metadata(key).await {
+        Ok(metadata) if metadata.is_dir => Err(StorageError::InvalidPath(
+            format!("Path refers to a directory: {:?}", key.as_str()).into(),
+        )),
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(StorageError::NotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{
+    config::Parsed,
+    hosts::{HostPattern, request_host},
+    routes::problem_response,
+};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode, Uri, header, uri::PathAndQuery},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use core::fmt;
+use globset::{Glob, GlobMatcher};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::{debug, warn};
+
+/// A rule as written in a site's configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    path: Option<String>,
+    regex: Option<String>,
+    rewrite: Option<String>,
+    redirect: Option<String>,
+    status: Option<u16>,
+    #[serde(default)]
+    hosts: Vec<Parsed<HostPattern>>,
+    #[serde(default)]
+    methods: Vec<Parsed<Method>>,
+    #[serde(default)]
+    query: BTreeMap<String, Parsed<Glob>>,
+}
+
+/// A rule that rewrites or redirects requests whose path matches a glob or regular expression,
+/// optionally only for some hosts, methods or query parameters.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RuleConfig")]
+pub struct Rule {
+    pattern: Pattern,
+    regex: Regex,
+    action: Action,
+    hosts: Vec<HostPattern>,
+    methods: Vec<Method>,
+    query: Vec<(String, GlobMatcher)>,
+}
+
+/// How a rule matches paths, as written in the configuration.
+#[derive(Debug, Clone)]
+enum Pattern {
+    Glob(Box<str>),
+    Regex(Box<str>),
+}
+
+/// What a rule does to the requests it matches, with `$1` or `${name}` in the target replaced by
+/// the captures of the path.
+#[derive(Debug, Clone)]
+enum Action {
+    Rewrite(Box<str>),
+    Redirect(StatusCode, Box<str>),
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = String;
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let (pattern, regex) = match (config.path, config.regex) {
+            (Some(glob), None) => {
+                let regex = glob_regex(&glob)?;
+                (Pattern::Glob(glob.into()), regex)
+            }
+            (None, Some(regex)) => {
+                let compiled =
+                    Regex::new(&regex).map_err(|err| format!("invalid regex {regex:?}: {err}"))?;
+                (Pattern::Regex(regex.into()), compiled)
+            }
+            _ => return Err("a rule must match either a `path` glob or a `regex`".to_owned()),
+        };
+        let action = match (config.rewrite, config.redirect, config.status) {
+            (Some(target), None, None) => {
+                if !target.starts_with('/') {
+                    return Err(format!(
+                        "invalid rewrite {target:?}, expected a path such as `/archive/$1`"
+                    ));
+                }
+                Action::Rewrite(target.into())
+            }
+            (None, Some(target), status) => {
+                if !["/", "http://", "https://"]
+                    .iter()
+                    .any(|prefix| target.starts_with(prefix))
+                {
+                    return Err(format!(
+                        "invalid redirect {target:?}, expected a path or an http(s) URL"
+                    ));
+                }
+                let status = match status.unwrap_or(302) {
+                    status @ (301 | 302 | 307 | 308) => {
+                        StatusCode::from_u16(status).expect("redirect statuses are valid")
+                    }
+                    status => {
+                        return Err(format!(
+                            "invalid redirect status {status}, expected 301, 302, 307 or 308"
+                        ));
+                    }
+                };
+                Action::Redirect(status, target.into())
+            }
+            (Some(_), None, Some(_)) => {
+                return Err("`status` only applies to redirects".to_owned());
+            }
+            _ => return Err("a rule must either `rewrite` or `redirect` requests".to_owned()),
+        };
+        let methods = config
+            .methods
+            .into_iter()
+            .map(|Parsed(method)| {
+                let name = method.as_str();
+                if name.bytes().any(|byte| byte.is_ascii_lowercase()) {
+                    return Err(format!(
+                        "invalid method {name:?}, methods are uppercase such as `GET`"
+                    ));
+                }
+                Ok(method)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            pattern,
+            regex,
+            action,
+            hosts: config.hosts.into_iter().map(|Parsed(host)| host).collect(),
+            methods,
+            query: config
+                .query
+                .into_iter()
+                .map(|(name, Parsed(glob))| (name, glob.compile_matcher()))
+                .collect(),
+        })
+    }
+}
+
+/// Translate a path glob into an anchored regular expression capturing each wildcard in order:
+/// `**` matches across segments, `*` within a single segment and `?` a single character.
+fn glob_regex(glob: &str) -> Result<Regex, String> {
+    if !glob.starts_with('/') {
+        return Err(format!(
+            "invalid path {glob:?}, expected a glob such as `/v1/**`"
+        ));
+    }
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '*' if chars.next_if_eq(&'*').is_some() => pattern.push_str("(.*)"),
+            '*' => pattern.push_str("([^/]*)"),
+            '?' => pattern.push_str("([^/])"),
+            char => pattern.push_str(&regex::escape(char.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).map_err(|err| format!("invalid path {glob:?}: {err}"))
+}
+
+impl Rule {
+    /// The captures of a request's path, if the request meets every condition of the rule.
+    fn captures<'a>(&self, req: &'a Request, host: Option<&str>) -> Option<Captures<'a>> {
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return None;
+        }
+        if !self.hosts.is_empty()
+            && !host.is_some_and(|host| self.hosts.iter().any(|pattern| pattern.matches(host)))
+        {
+            return None;
+        }
+        let query = req.uri().query().unwrap_or_default().as_bytes();
+        if !self.query.iter().all(|(name, glob)| {
+            form_urlencoded::parse(query)
+                .any(|(key, value)| key == **name && glob.is_match(&*value))
+        }) {
+            return None;
+        }
+        self.regex.captures(req.uri().path())
+    }
+
+    /// What the rule does to a request it matched. The request's query is kept unless the target
+    /// has its own.
+    fn outcome(&self, uri: &Uri, captures: &Captures) -> Result<Outcome, String> {
+        let (Action::Rewrite(target) | Action::Redirect(_, target)) = &self.action;
+        let mut location = String::new();
+        captures.expand(target, &mut location);
+        if let Some(query) = uri.query().filter(|_| !location.contains('?')) {
+            location.push('?');
+            location.push_str(query);
+        }
+        let invalid = || format!("produced an invalid URL {location:?} for {uri}");
+        match &self.action {
+            Action::Rewrite(_) => PathAndQuery::try_from(location.as_str())
+                .map(Outcome::Rewrite)
+                .map_err(|_| invalid()),
+            Action::Redirect(status, _) => HeaderValue::try_from(location.as_str())
+                .map(|location| Outcome::Redirect(*status, location))
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Pattern::Glob(glob) => write!(f, "path {glob:?}")?,
+            Pattern::Regex(regex) => write!(f, "regex {regex:?}")?,
+        }
+        if !self.hosts.is_empty() {
+            let hosts = self.hosts.iter().map(ToString::to_string);
+            write!(f, " on {}", hosts.collect::<Vec<_>>().join(", "))?;
+        }
+        if !self.methods.is_empty() {
+            let methods = self.methods.iter().map(Method::as_str);
+            write!(f, " for {}", methods.collect::<Vec<_>>().join(", "))?;
+        }
+        for (name, glob) in &self.query {
+            write!(f, " with {name}={}", glob.glob())?;
+        }
+        match &self.action {
+            Action::Rewrite(target) => write!(f, ", rewriting to {target:?}"),
+            Action::Redirect(status, target) => {
+                write!(f, ", redirecting with {} to {target:?}", status.as_u16())
+            }
+        }
+    }
+}
+
+/// What a rule does to a request it matched.
+pub enum Outcome {
+    /// Serve the request as if it had been made for another path and query.
+    Rewrite(PathAndQuery),
+    /// Redirect the client elsewhere.
+    Redirect(StatusCode, HeaderValue),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Rewrite(path) => write!(f, "Rewritten to {path}"),
+            Outcome::Redirect(status, location) => write!(
+                f,
+                "Redirected with {status} to {}",
+                location.to_str().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// The rules of a site, of which only the first matching a request is applied.
+#[derive(Debug, Default)]
+pub struct Rules(Box<[Rule]>);
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self(rules.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The first rule matching a request, along with its index and what it does to the request.
+    pub fn find(&self, req: &Request) -> Option<(usize, &Rule, Result<Outcome, String>)> {
+        let host = request_host(req);
+        self.0.iter().enumerate().find_map(|(index, rule)| {
+            let captures = rule.captures(req, host.as_deref())?;
+            Some((index, rule, rule.outcome(req.uri(), &captures)))
+        })
+    }
+}
+
+/// The URI a request was rewritten to, added to its response so the access log can find the
+/// storage backend that served it.
+#[derive(Clone)]
+pub struct Rewritten(pub Uri);
+
+/// Middleware that applies the first of a site's rules matching each request, before it is routed.
+pub async fn apply(State(rules): State<Arc<Rules>>, mut req: Request, next: Next) -> Response {
+    let Some((index, _, outcome)) = rules.find(&req) else {
+        return next.run(req).await;
+    };
+    match outcome {
+        Ok(Outcome::Rewrite(path)) => {
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(path);
+            let uri = Uri::from_parts(parts).expect("replacing the path keeps the URI valid");
+            debug!("Rewriting {} to {uri} by rule {index}", req.uri());
+            *req.uri_mut() = uri.clone();
+            let mut res = next.run(req).await;
+            res.extensions_mut().insert(Rewritten(uri));
+            res
+        }
+        Ok(Outcome::Redirect(status, location)) => {
+            debug!("Redirecting {} by rule {index}", req.uri());
+            (status, [(header::LOCATION, location)]).into_response()
+        }
+        Err(err) => {
+            warn!("Rule {index} {err}");
+            problem_response(StatusCode::INTERNAL_SERVER_ERROR, None, req.headers()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(toml: &str) -> Rule {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// What `rule` does to a `GET` request for `uri`, if it matches.
+    fn apply_to(rule: &Rule, uri: &str) -> Option<String> {
+        let req = Request::get(uri).body(axum::body::Body::empty()).unwrap();
+        let captures = rule.captures(&req, None)?;
+        Some(match rule.outcome(req.uri(), &captures).unwrap() {
+            Outcome::Rewrite(path) => path.to_string(),
+            Outcome::Redirect(_, location) => location.to_str().unwrap().to_owned(),
+        })
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let regex = glob_regex("/v1/**").unwrap();
+        assert!(regex.is_match("/v1/a/b/c"));
+        assert!(regex.is_match("/v1/"));
+        assert!(!regex.is_match("/v2/a"));
+
+        let regex = glob_regex("/*/index.html").unwrap();
+        assert!(regex.is_match("/docs/index.html"));
+        assert!(!regex.is_match("/docs/v1/index.html"));
+
+        let regex = glob_regex("/v?/file").unwrap();
+        assert!(regex.is_match("/v1/file"));
+        assert!(!regex.is_match("/v10/file"));
+        assert!(!regex.is_match("/v//file"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_syntax() {
+        let regex = glob_regex("/a.b+(c)").unwrap();
+        assert!(regex.is_match("/a.b+(c)"));
+        assert!(!regex.is_match("/axb+(c)"));
+        assert!(!regex.is_match("/a.bb(c)"));
+    }
+
+    #[test]
+    fn glob_must_be_a_path() {
+        assert!(glob_regex("v1/**").is_err());
+    }
+
+    #[test]
+    fn glob_captures_expand_in_order() {
+        let rule = rule(
+            r#"
+            path = "/*/v?/**"
+            rewrite = "/archive/$2/$1/$3"
+            "#,
+        );
+        assert_eq!(
+            apply_to(&rule, "/docs/v1/a/b.html").as_deref(),
+            Some("/archive/1/docs/a/b.html")
+        );
+        assert_eq!(apply_to(&rule, "/docs/latest/a.html"), None);
+    }
+
+    #[test]
+    fn named_captures_expand_and_query_is_kept() {
+        let rule = rule(
+            r#"
+            regex = "^/users/(?<user>[^/]+)$"
+            redirect = "https://example.com/u/${user}"
+            "#,
+        );
+        assert_eq!(
+            apply_to(&rule, "/users/ada?tab=files").as_deref(),
+            Some("https://example.com/u/ada?tab=files")
+        );
+    }
+
+    #[test]
+    fn target_query_replaces_the_request_query() {
+        let rule = rule(
+            r#"
+            path = "/search/*"
+            rewrite = "/index.html?q=$1"
+            "#,
+        );
+        assert_eq!(
+            apply_to(&rule, "/search/rust?page=2").as_deref(),
+            Some("/index.html?q=rust")
+        );
+    }
+}
@@ -1,7 +1,14 @@
-use crate::storage::{FileMetadata, StorageOperations};
+use super::local::{
+    delete_file, file_metadata, list_directory, open_file, open_range, rename_file, resolve_parent,
+    resolve_path, write_file,
+};
+use crate::storage::{
+    DirectoryListing, FileMetadata, StorageError, StorageKey, StorageOperations, StorageResult,
+};
 use anyhow::Result;
-use std::path::{Component, Path, PathBuf};
-use tokio::io::{self, AsyncRead};
+use faccess::{AccessMode, PathExt};
+use std::{ops::Range, path::Path};
+use tokio::io::AsyncRead;
 use tracing::debug;
 
 #[derive(Debug)]
@@ -17,53 +24,84 @@ impl FilesystemStorage {
             base_path: std::fs::canonicalize(base_path)?.into_boxed_path(),
         })
     }
-}
 
-impl FilesystemStorage {
-    fn join_to_base(&self, path: &Path) -> Result<PathBuf> {
-        for component in path.components() {
-            match component {
-                Component::Prefix(_) | Component::RootDir => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::PermissionDenied,
-                        format!("Absolute paths are not allowed: {path:?}"),
-                    )
-                    .into());
-                }
-                Component::ParentDir => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::PermissionDenied,
-                        format!("Paths cannot reference a parent directory: {path:?}"),
-                    )
-                    .into());
-                }
-                _ => {}
-            }
-        }
-        Ok(self.base_path.join(path))
+    /// The directory files are served from.
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
     }
 }
 
 impl StorageOperations for FilesystemStorage {
-    async fn read_stream(&self, path: &Path) -> Result<Option<Box<dyn AsyncRead + Unpin + Send>>> {
-        let path = self.base_path.join(path);
+    async fn read_stream(
+        &self,
+        key: &StorageKey,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = resolve_path(&self.base_path, key).await?;
         debug!("Reading file at {path:?}");
-        match tokio::fs::File::open(&path).await {
-            Ok(file) => Ok(Some(Box::new(file))),
-            Err(err) if err.kind() == tokio::io::ErrorKind::NotFound => Ok(None),
-            Err(err) => Err(err.into()),
-        }
+        Ok(Box::new(open_file(&path).await?))
     }
 
-    async fn metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
-        let path = self.join_to_base(path)?;
+    async fn read_range(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = resolve_path(&self.base_path, key).await?;
+        debug!("Reading bytes {range:?} of file at {path:?}");
+        open_range(&path, range).await
+    }
+
+    async fn metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        let path = resolve_path(&self.base_path, key).await?;
         debug!("Reading file metadata at {path:?}");
-        match tokio::fs::metadata(&path).await {
-            Ok(metadata) => Ok(Some(FileMetadata {
-                file_size: metadata.len().try_into()?,
-            })),
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
-            Err(err) => Err(err.into()),
+        Ok(file_metadata(&tokio::fs::metadata(&path).await?, true))
+    }
+
+    async fn list(
+        &self,
+        key: Option<&StorageKey>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<DirectoryListing> {
+        let path = match key {
+            Some(key) => resolve_path(&self.base_path, key).await?,
+            None => self.base_path.to_path_buf(),
+        };
+        debug!("Listing directory at {path:?}");
+        list_directory(&self.base_path, &path, cursor, limit).await
+    }
+
+    async fn write_stream(
+        &self,
+        key: &StorageKey,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+    ) -> StorageResult<()> {
+        let path = resolve_parent(&self.base_path, key, true).await?;
+        debug!("Writing file at {path:?}");
+        write_file(&path, reader).await
+    }
+
+    async fn delete(&self, key: &StorageKey) -> StorageResult<()> {
+        let path = resolve_parent(&self.base_path, key, false).await?;
+        debug!("Deleting file at {path:?}");
+        delete_file(&path).await
+    }
+
+    async fn rename(&self, from: &StorageKey, to: &StorageKey) -> StorageResult<()> {
+        let from = resolve_parent(&self.base_path, from, false).await?;
+        let to = resolve_parent(&self.base_path, to, true).await?;
+        debug!("Moving file at {from:?} to {to:?}");
+        rename_file(&from, &to).await
+    }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        if !tokio::fs::metadata(&self.base_path).await?.is_dir() {
+            return Err(StorageError::Unavailable(
+                format!("{:?} is not a directory", self.base_path).into(),
+            ));
         }
+        // Only reading is checked, as sites that don't accept writes may be served read-only.
+        self.base_path.access(AccessMode::READ)?;
+        Ok(())
     }
 }
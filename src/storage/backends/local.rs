@@ -0,0 +1,276 @@
+use crate::storage::{
+    DirectoryEntry, DirectoryListing, FileMetadata, StorageError, StorageKey, StorageResult,
+};
+use std::{
+    io::{ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Resolve a storage key to a path on a locally mounted filesystem.
+///
+/// Symlinks are followed and the result is rejected if it ends up outside of `base_path`.
+pub async fn resolve_path(base_path: &Path, key: &StorageKey) -> StorageResult<PathBuf> {
+    let path = tokio::fs::canonicalize(base_path.join(key.as_path())).await?;
+    ensure_within(base_path, &path, key)?;
+    Ok(path)
+}
+
+/// Resolve a storage key to a path on a locally mounted filesystem for modifying it, optionally
+/// creating any missing parent directories.
+///
+/// Only the parent is canonicalized, so the final component may not exist yet and is never
+/// followed if it is a symlink (e.g. deleting a symlink removes the link rather than its target).
+pub async fn resolve_parent(
+    base_path: &Path,
+    key: &StorageKey,
+    create_parents: bool,
+) -> StorageResult<PathBuf> {
+    let path = base_path.join(key.as_path());
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(StorageError::InvalidPath(
+            format!("Key does not refer to a file: {:?}", key.as_str()).into(),
+        ));
+    };
+    if create_parents {
+        // Check the deepest existing ancestor first so that missing directories are never created
+        // beneath a symlink that escapes the storage root.
+        for ancestor in parent.ancestors() {
+            if tokio::fs::try_exists(ancestor).await? {
+                ensure_within(base_path, &tokio::fs::canonicalize(ancestor).await?, key)?;
+                break;
+            }
+        }
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|err| match err.kind() {
+                ErrorKind::AlreadyExists | ErrorKind::NotADirectory => StorageError::InvalidPath(
+                    format!("A parent of {:?} is not a directory", key.as_str()).into(),
+                ),
+                _ => err.into(),
+            })?;
+    }
+    let parent = tokio::fs::canonicalize(parent).await?;
+    ensure_within(base_path, &parent, key)?;
+    Ok(parent.join(name))
+}
+
+fn ensure_within(base_path: &Path, path: &Path, key: &StorageKey) -> StorageResult<()> {
+    match path.starts_with(base_path) {
+        true => Ok(()),
+        false => Err(StorageError::Forbidden(
+            format!("Path resolves outside of the storage root: {key:?}").into(),
+        )),
+    }
+}
+
+/// Open the file at `path`, treating directories as if they do not exist.
+pub async fn open_file(path: &Path) -> StorageResult<tokio::fs::File> {
+    let file = tokio::fs::File::open(path).await?;
+    if file.metadata().await?.is_dir() {
+        return Err(StorageError::NotFound);
+    }
+    Ok(file)
+}
+
+/// Open the file at `path` and limit reads to the bytes within `range`.
+pub async fn open_range(
+    path: &Path,
+    range: Range<usize>,
+) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+    let mut file = open_file(path).await?;
+    file.seek(SeekFrom::Start(range.start as u64)).await?;
+    Ok(Box::new(file.take((range.end - range.start) as u64)))
+}
+
+/// Write `reader` to `path` by streaming it into a temporary sibling file and renaming that into
+/// place, so readers never observe a partially written file.
+pub async fn write_file(
+    path: &Path,
+    mut reader: Box<dyn AsyncRead + Unpin + Send>,
+) -> StorageResult<()> {
+    if tokio::fs::symlink_metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return Err(StorageError::InvalidPath(
+            "Cannot replace a directory with a file".into(),
+        ));
+    }
+    let temp_path = temp_sibling(path);
+    let result = async {
+        let mut file = tokio::fs::File::create_new(&temp_path).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    Ok(result?)
+}
+
+/// Delete the file at `path`, treating directories as if they do not exist.
+pub async fn delete_file(path: &Path) -> StorageResult<()> {
+    if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        return Err(StorageError::NotFound);
+    }
+    Ok(tokio::fs::remove_file(path).await?)
+}
+
+/// Move the file at `from` to `to`, treating directories at `from` as if they do not exist.
+pub async fn rename_file(from: &Path, to: &Path) -> StorageResult<()> {
+    if tokio::fs::symlink_metadata(from).await?.is_dir() {
+        return Err(StorageError::NotFound);
+    }
+    if tokio::fs::symlink_metadata(to)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return Err(StorageError::InvalidPath(
+            "Cannot replace a directory with a file".into(),
+        ));
+    }
+    Ok(tokio::fs::rename(from, to).await?)
+}
+
+/// A hidden, unique path next to `path` to write into before renaming it into place.
+fn temp_sibling(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{name}.{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Build file metadata from a local stat, deriving a strong entity tag from its size and mtime.
+///
+/// The inode is only mixed into the tag when `stable_inode` is set, as some filesystems (e.g. FUSE
+/// mounts) hand out inode numbers that change across remounts.
+pub fn file_metadata(metadata: &std::fs::Metadata, stable_inode: bool) -> FileMetadata {
+    let last_modified = metadata.modified().ok();
+    let mtime_nanos = last_modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    #[cfg(unix)]
+    let inode = if stable_inode {
+        std::os::unix::fs::MetadataExt::ino(metadata)
+    } else {
+        0
+    };
+    #[cfg(not(unix))]
+    let inode = {
+        let _ = stable_inode;
+        0
+    };
+    FileMetadata {
+        file_size: metadata.len() as usize,
+        is_dir: metadata.is_dir(),
+        last_modified,
+        etag: Some(format!("\"{inode:x}-{mtime_nanos:x}-{:x}\"", metadata.len()).into_boxed_str()),
+    }
+}
+
+/// List a page of the directory at `path`, ordered by name and resumed after the `cursor` name.
+///
+/// Symlinks that resolve outside of `base_path` are omitted as they could never be served.
+pub async fn list_directory(
+    base_path: &Path,
+    path: &Path,
+    cursor: Option<&str>,
+    limit: usize,
+) -> StorageResult<DirectoryListing> {
+    let limit = limit.max(1);
+    let mut read_dir = tokio::fs::read_dir(path).await.map_err(|err| {
+        if err.kind() == std::io::ErrorKind::NotADirectory {
+            StorageError::NotFound
+        } else {
+            err.into()
+        }
+    })?;
+
+    let mut names = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        // Entries that aren't valid UTF-8 can never be requested, so don't list them.
+        if let Ok(name) = entry.file_name().into_string()
+            && cursor.is_none_or(|cursor| name.as_str() > cursor)
+        {
+            let is_symlink = entry.file_type().await?.is_symlink();
+            names.push((name, is_symlink));
+        }
+    }
+    names.sort_unstable();
+
+    let mut entries = Vec::with_capacity(names.len().min(limit));
+    for (name, is_symlink) in names.iter().take(limit) {
+        if *is_symlink
+            && !tokio::fs::canonicalize(path.join(name))
+                .await
+                .is_ok_and(|target| target.starts_with(base_path))
+        {
+            continue;
+        }
+        // Broken symlinks and entries removed since being read are silently skipped.
+        let Ok(metadata) = tokio::fs::metadata(path.join(name)).await else {
+            continue;
+        };
+        entries.push(DirectoryEntry {
+            name: name.as_str().into(),
+            is_dir: metadata.is_dir(),
+            file_size: if metadata.is_dir() {
+                0
+            } else {
+                metadata.len() as usize
+            },
+            last_modified: metadata.modified().ok(),
+        });
+    }
+
+    Ok(DirectoryListing {
+        next_cursor: (names.len() > limit).then(|| names[limit - 1].0.as_str().into()),
+        entries,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[tokio::test]
+    async fn symlinks_cannot_escape_the_root() {
+        let dir = TempDir::new("local-symlinks");
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("public.txt"), "public").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("escape.txt")).unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+
+        let key = StorageKey::new("public.txt").unwrap();
+        assert_eq!(
+            resolve_path(&root, &key).await.unwrap(),
+            root.join("public.txt")
+        );
+        let key = StorageKey::new("escape.txt").unwrap();
+        assert!(matches!(
+            resolve_path(&root, &key).await,
+            Err(StorageError::Forbidden(_))
+        ));
+        assert!(matches!(
+            resolve_parent(&root, &key, false).await,
+            Ok(path) if path == root.join("escape.txt")
+        ));
+    }
+}
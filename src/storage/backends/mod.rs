@@ -17,3 +17,6 @@ pub use s3::S3Storage;
 mod sshfs;
 #[cfg(feature = "storage-sshfs")]
 pub use sshfs::SSHFSStorage;
+
+#[cfg(any(feature = "storage-filesystem", feature = "storage-sshfs"))]
+mod local;
@@ -1,18 +1,53 @@
-use crate::storage::{FileMetadata, StorageOperations};
+use crate::{
+    metrics,
+    request_context::{self, X_REQUEST_ID},
+    storage::{
+        DirectoryEntry, DirectoryListing, FileMetadata, StorageError, StorageKey,
+        StorageOperations, StorageResult,
+    },
+};
 use anyhow::{Context, Result, anyhow, bail};
-use aws_sdk_s3::Client;
-use std::path::Path;
-use tokio::io::AsyncRead;
+use aws_sdk_s3::{
+    Client,
+    config::{
+        ConfigBag, Intercept, RuntimeComponents, http::HttpResponse,
+        interceptors::BeforeTransmitInterceptorContextMut,
+    },
+    error::{BoxError, DisplayErrorContext, SdkError},
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::{io, ops::Range, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
+/// Objects larger than this are uploaded in parts of this size rather than in a single request.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Characters that must be escaped in the key of a `CopyObject` source.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
 #[derive(Debug)]
 pub struct S3Storage {
     client: Client,
     bucket: Box<str>,
+    /// The prefix of every object's key, empty or ending with a slash.
+    prefix: Box<str>,
 }
 
 impl S3Storage {
-    pub fn new<B: Into<Box<str>>>(bucket: B) -> Result<Self> {
+    /// Connect to the bucket, creating it if it doesn't exist and `create` is set.
+    pub fn new<B: Into<Box<str>>, P: Into<Box<str>>>(
+        bucket: B,
+        prefix: P,
+        create: bool,
+    ) -> Result<Self> {
         let bucket = bucket.into();
         let client = std::thread::spawn({
             let bucket = bucket.clone();
@@ -21,9 +56,15 @@ impl S3Storage {
                     .context("Failed to create Tokio runtime")?
                     .block_on(async move {
                         let config = aws_config::from_env().load().await;
-                        let client = Client::new(&config);
+                        let client = Client::from_conf(
+                            aws_sdk_s3::config::Builder::from(&config)
+                                .interceptor(RequestIdInterceptor)
+                                .build(),
+                        );
                         if let Err(err) = client.head_bucket().bucket(&*bucket).send().await {
-                            if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
+                            if create
+                                && err.as_service_error().map(|e| e.is_not_found()) == Some(true)
+                            {
                                 client
                                     .create_bucket()
                                     .bucket(&*bucket)
@@ -44,52 +85,400 @@ impl S3Storage {
         })
         .join()
         .map_err(|e| anyhow!("S3 client thread panicked: {e:?}"))??;
-        Ok(Self { client, bucket })
+        Ok(Self {
+            client,
+            bucket,
+            prefix: prefix.into(),
+        })
+    }
+
+    /// The bucket files are served from.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// The key of the object storing a file.
+    fn object_key(&self, key: &StorageKey) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    /// Treat a key with no object of its own as a directory if any objects exist beneath it.
+    async fn prefix_metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        debug!("Checking if {key:?} is a prefix in bucket {}", self.bucket);
+        let output = self
+            .client
+            .list_objects_v2()
+            .bucket(&*self.bucket)
+            .prefix(format!("{}/", self.object_key(key)))
+            .max_keys(1)
+            .send()
+            .await
+            .map_err(storage_error)?;
+        if output.key_count.unwrap_or_default() == 0 {
+            return Err(StorageError::NotFound);
+        }
+        Ok(FileMetadata {
+            file_size: 0,
+            is_dir: true,
+            last_modified: None,
+            etag: None,
+        })
+    }
+
+    /// Upload the remainder of a multipart upload, starting with the already read `part`.
+    async fn upload_parts(
+        &self,
+        key: &StorageKey,
+        upload_id: &str,
+        mut part: Vec<u8>,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> StorageResult<Vec<CompletedPart>> {
+        let mut parts = Vec::new();
+        while !part.is_empty() {
+            let part_number = parts.len() as i32 + 1;
+            let is_last = part.len() < MULTIPART_PART_SIZE;
+            debug!(
+                "Uploading part {part_number} of {key:?} to bucket {}",
+                self.bucket
+            );
+            let output = self
+                .client
+                .upload_part()
+                .bucket(&*self.bucket)
+                .key(self.object_key(key))
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part))
+                .send()
+                .await
+                .map_err(storage_error)?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(output.e_tag)
+                    .build(),
+            );
+            part = match is_last {
+                true => Vec::new(),
+                false => read_part(reader).await?,
+            };
+        }
+        Ok(parts)
+    }
+}
+
+/// Sends the ID of the request being handled along with every S3 request, so they can be
+/// correlated in the logs of S3-compatible servers and proxies.
+#[derive(Debug)]
+struct RequestIdInterceptor;
+
+impl Intercept for RequestIdInterceptor {
+    fn name(&self) -> &'static str {
+        "RequestIdInterceptor"
+    }
+
+    fn modify_before_signing(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(request_id) = request_context::request_id()
+            && let Ok(request_id) = request_id.to_str()
+        {
+            context
+                .request_mut()
+                .headers_mut()
+                .insert(X_REQUEST_ID.as_str().to_owned(), request_id.to_owned());
+        }
+        Ok(())
     }
 }
 
 impl StorageOperations for S3Storage {
-    async fn read_stream(&self, path: &Path) -> Result<Option<Box<dyn AsyncRead + Unpin + Send>>> {
-        debug!("Opening stream for {path:?} from bucket {}", self.bucket);
-        match self
+    async fn read_stream(
+        &self,
+        key: &StorageKey,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        debug!("Opening stream for {key:?} from bucket {}", self.bucket);
+        let output = self
             .client
             .get_object()
             .bucket(&*self.bucket)
-            .key(path.to_str().context("failed to convert path to str")?)
+            .key(self.object_key(key))
             .send()
             .await
-        {
-            Ok(output) => Ok(Some(Box::new(output.body.into_async_read()))),
-            Err(err) => {
-                if err.as_service_error().map(|e| e.is_no_such_key()) == Some(true) {
-                    Ok(None)
-                } else {
-                    Err(err.into())
-                }
-            }
+            .map_err(storage_error)?;
+        Ok(Box::new(output.body.into_async_read()))
+    }
+
+    async fn read_range(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        debug!(
+            "Opening stream for bytes {range:?} of {key:?} from bucket {}",
+            self.bucket
+        );
+        let output = self
+            .client
+            .get_object()
+            .bucket(&*self.bucket)
+            .key(self.object_key(key))
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(Box::new(output.body.into_async_read()))
+    }
+
+    async fn metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        match self.file_metadata(key).await {
+            Err(StorageError::NotFound) => self.prefix_metadata(key).await,
+            result => result,
         }
     }
 
-    async fn metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
-        debug!("Checking if {path:?} exists in bucket {}", self.bucket);
-        match self
+    async fn file_metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        debug!("Checking if {key:?} exists in bucket {}", self.bucket);
+        let data = self
             .client
             .head_object()
             .bucket(&*self.bucket)
-            .key(path.to_str().context("failed to convert path to str")?)
+            .key(self.object_key(key))
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(FileMetadata {
+            file_size: data
+                .content_length
+                .unwrap_or_default()
+                .try_into()
+                .context("S3 returned an invalid content length")?,
+            is_dir: false,
+            last_modified: data
+                .last_modified
+                .and_then(|time| SystemTime::try_from(time).ok()),
+            etag: data.e_tag.map(String::into_boxed_str),
+        })
+    }
+
+    async fn list(
+        &self,
+        key: Option<&StorageKey>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<DirectoryListing> {
+        let prefix = match key {
+            Some(key) => format!("{}/", self.object_key(key)),
+            None => self.prefix.to_string(),
+        };
+        debug!("Listing prefix {prefix:?} in bucket {}", self.bucket);
+        let output = self
+            .client
+            .list_objects_v2()
+            .bucket(&*self.bucket)
+            .prefix(&prefix)
+            .delimiter("/")
+            .max_keys(limit.clamp(1, 1000) as i32)
+            .set_continuation_token(cursor.map(str::to_string))
+            .send()
+            .await
+            .map_err(storage_error)?;
+
+        let directories = output
+            .common_prefixes()
+            .iter()
+            .filter_map(|common| common.prefix()?.strip_prefix(&prefix))
+            .map(|name| DirectoryEntry {
+                name: name.trim_end_matches('/').into(),
+                is_dir: true,
+                file_size: 0,
+                last_modified: None,
+            });
+        let files = output.contents().iter().filter_map(|object| {
+            // Skip the zero-byte "folder" marker some tools create for the prefix itself.
+            let name = object.key()?.strip_prefix(&prefix)?;
+            (!name.is_empty()).then(|| DirectoryEntry {
+                name: name.into(),
+                is_dir: false,
+                file_size: object
+                    .size()
+                    .unwrap_or_default()
+                    .try_into()
+                    .unwrap_or_default(),
+                last_modified: object
+                    .last_modified()
+                    .and_then(|time| SystemTime::try_from(*time).ok()),
+            })
+        });
+        let mut entries = directories.chain(files).collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        // S3 has no concept of directories, so an empty prefix is the closest thing to one not existing.
+        if entries.is_empty() && key.is_some() && cursor.is_none() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(DirectoryListing {
+            entries,
+            next_cursor: output
+                .next_continuation_token
+                .filter(|_| output.is_truncated == Some(true))
+                .map(String::into_boxed_str),
+        })
+    }
+
+    async fn write_stream(
+        &self,
+        key: &StorageKey,
+        mut reader: Box<dyn AsyncRead + Unpin + Send>,
+    ) -> StorageResult<()> {
+        let first_part = read_part(&mut reader).await?;
+        if first_part.len() < MULTIPART_PART_SIZE {
+            debug!("Uploading {key:?} to bucket {}", self.bucket);
+            self.client
+                .put_object()
+                .bucket(&*self.bucket)
+                .key(self.object_key(key))
+                .body(ByteStream::from(first_part))
+                .send()
+                .await
+                .map_err(storage_error)?;
+            return Ok(());
+        }
+
+        debug!(
+            "Starting multipart upload of {key:?} to bucket {}",
+            self.bucket
+        );
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(&*self.bucket)
+            .key(self.object_key(key))
             .send()
             .await
+            .map_err(storage_error)?
+            .upload_id
+            .context("S3 did not return a multipart upload ID")?;
+        let parts = match self
+            .upload_parts(key, &upload_id, first_part, &mut reader)
+            .await
         {
-            Ok(data) => Ok(Some(FileMetadata {
-                file_size: data.content_length.unwrap_or_default().try_into()?,
-            })),
+            Ok(parts) => parts,
             Err(err) => {
-                if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
-                    Ok(None)
-                } else {
-                    Err(err.into())
-                }
+                debug!(
+                    "Aborting multipart upload of {key:?} to bucket {}",
+                    self.bucket
+                );
+                let _ = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&*self.bucket)
+                    .key(self.object_key(key))
+                    .upload_id(&upload_id)
+                    .send()
+                    .await;
+                return Err(err);
             }
-        }
+        };
+        self.client
+            .complete_multipart_upload()
+            .bucket(&*self.bucket)
+            .key(self.object_key(key))
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &StorageKey) -> StorageResult<()> {
+        // S3 reports success when deleting objects that don't exist, so check for it first.
+        self.file_metadata(key).await?;
+        debug!("Deleting {key:?} from bucket {}", self.bucket);
+        self.client
+            .delete_object()
+            .bucket(&*self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    /// S3 has no rename operation, so the object is copied and then deleted. Copies are limited
+    /// to objects of up to 5GB by S3.
+    async fn rename(&self, from: &StorageKey, to: &StorageKey) -> StorageResult<()> {
+        self.file_metadata(from).await?;
+        debug!("Moving {from:?} to {to:?} in bucket {}", self.bucket);
+        self.client
+            .copy_object()
+            .bucket(&*self.bucket)
+            .key(self.object_key(to))
+            .copy_source(format!(
+                "{}/{}",
+                self.bucket,
+                utf8_percent_encode(&self.object_key(from), COPY_SOURCE)
+            ))
+            .send()
+            .await
+            .map_err(storage_error)?;
+        self.client
+            .delete_object()
+            .bucket(&*self.bucket)
+            .key(self.object_key(from))
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(())
     }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        self.client
+            .head_bucket()
+            .bucket(&*self.bucket)
+            .send()
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+/// Read up to [`MULTIPART_PART_SIZE`] bytes from `reader`, returning fewer only at the end of the stream.
+async fn read_part(reader: &mut (dyn AsyncRead + Unpin + Send)) -> io::Result<Vec<u8>> {
+    let mut part = Vec::new();
+    reader
+        .take(MULTIPART_PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .await?;
+    Ok(part)
+}
+
+/// Map an S3 SDK error into a [`StorageError`] based on the failure kind and response status.
+fn storage_error<E>(err: SdkError<E, HttpResponse>) -> StorageError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let reason = || DisplayErrorContext(&err).to_string().into_boxed_str();
+    let error = match (&err, err.raw_response().map(|res| res.status().as_u16())) {
+        (_, Some(404)) => StorageError::NotFound,
+        (_, Some(401 | 403)) => StorageError::Forbidden(reason()),
+        (SdkError::TimeoutError(_), _) | (_, Some(504)) => StorageError::Timeout(reason()),
+        (SdkError::DispatchFailure(failure), _) if failure.is_timeout() => {
+            StorageError::Timeout(reason())
+        }
+        (SdkError::DispatchFailure(_), _) | (_, Some(500 | 502 | 503)) => {
+            StorageError::Unavailable(reason())
+        }
+        _ => StorageError::Backend(err.into()),
+    };
+    metrics::record_s3_error(&error);
+    error
 }
@@ -1,7 +1,13 @@
-use crate::storage::{FileMetadata, StorageOperations};
+use super::local::{
+    delete_file, file_metadata, list_directory, open_file, open_range, rename_file, resolve_parent,
+    resolve_path, write_file,
+};
+use crate::storage::{
+    DirectoryListing, FileMetadata, StorageError, StorageKey, StorageOperations, StorageResult,
+};
 use anyhow::{Context, Result, bail};
-use std::{io::Write, path::Path, process::Command};
-use tokio::io::{self, AsyncRead};
+use std::{io::Write, ops::Range, path::Path, process::Command};
+use tokio::io::AsyncRead;
 use tracing::debug;
 
 const SSHFS_BIN: &str = "sshfs";
@@ -14,6 +20,7 @@ pub struct SSHFSStorage {
     connection_string: Box<str>,
     password: Option<Box<str>>,
     options: Option<Box<[String]>>,
+    read_write: bool,
 }
 
 impl SSHFSStorage {
@@ -40,6 +47,8 @@ impl SSHFSStorage {
                     .filter(|s| !s.is_empty())
                     .collect()
             }),
+            read_write: std::env::var("SSHFS_READ_WRITE")
+                .is_ok_and(|value| matches!(value.trim(), "1" | "true")),
         };
         storage.mount()?;
         Ok(storage)
@@ -74,12 +83,15 @@ impl SSHFSStorage {
             .arg("-o")
             .arg("ConnectTimeout=10") // Only wait 10 seconds for the connection
             .arg("-o")
-            .arg("ro") // Mount as a read-only filesystem
-            .arg("-o")
             .arg("ServerAliveInterval=15") // Keep-Alive ping every 15 seconds.
             .arg("-o")
             .arg("reconnect"); // Automatically reconnect on disconnect.
 
+        // Mount as a read-only filesystem unless writes have been explicitly allowed.
+        if !self.read_write {
+            sshfs_cmd.arg("-o").arg("ro");
+        }
+
         // Append additional user-provided options.
         if let Some(ref options) = self.options {
             for option in options {
@@ -113,6 +125,34 @@ impl SSHFSStorage {
     }
 }
 
+impl SSHFSStorage {
+    /// The directory the remote filesystem is mounted at.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Whether the mountpoint is currently a FUSE mount, as it stops being one if sshfs exits.
+    pub fn is_mounted(&self) -> bool {
+        let Ok(mounts) = std::fs::read_to_string("/proc/self/mounts") else {
+            return false;
+        };
+        // Spaces and other whitespace in mountpoints are octal escaped.
+        let mountpoint = self
+            .mountpoint
+            .to_string_lossy()
+            .replace(' ', "\\040")
+            .replace('\t', "\\011")
+            .replace('\n', "\\012");
+        mounts.lines().any(|line| {
+            let mut fields = line.split(' ');
+            fields.nth(1) == Some(&mountpoint)
+                && fields
+                    .next()
+                    .is_some_and(|fstype| fstype.starts_with("fuse"))
+        })
+    }
+}
+
 impl Drop for SSHFSStorage {
     fn drop(&mut self) {
         debug!("Unmounting SSHFS mountpoint using {FUSERMOUNT_BIN} as backend has been dropped");
@@ -125,25 +165,77 @@ impl Drop for SSHFSStorage {
 }
 
 impl StorageOperations for SSHFSStorage {
-    async fn read_stream(&self, path: &Path) -> Result<Option<Box<dyn AsyncRead + Unpin + Send>>> {
-        let path = Path::new(&*self.mountpoint).join(path);
-        debug!("Reading file stream {path:?}");
-        match tokio::fs::File::open(&path).await {
-            Ok(file) => Ok(Some(Box::new(file))),
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
-            Err(err) => Err(err.into()),
-        }
+    async fn read_stream(
+        &self,
+        key: &StorageKey,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = resolve_path(&self.mountpoint, key).await?;
+        debug!("Reading file at {path:?}");
+        Ok(Box::new(open_file(&path).await?))
+    }
+
+    async fn read_range(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = resolve_path(&self.mountpoint, key).await?;
+        debug!("Reading bytes {range:?} of file at {path:?}");
+        open_range(&path, range).await
     }
 
-    async fn metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
-        let path = Path::new(&*self.mountpoint).join(path);
+    async fn metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        let path = resolve_path(&self.mountpoint, key).await?;
         debug!("Reading file metadata at {path:?}");
-        match tokio::fs::metadata(&path).await {
-            Ok(metadata) => Ok(Some(FileMetadata {
-                file_size: metadata.len().try_into()?,
-            })),
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
-            Err(err) => Err(err.into()),
+        Ok(file_metadata(&tokio::fs::metadata(&path).await?, false))
+    }
+
+    async fn list(
+        &self,
+        key: Option<&StorageKey>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<DirectoryListing> {
+        let path = match key {
+            Some(key) => resolve_path(&self.mountpoint, key).await?,
+            None => self.mountpoint.to_path_buf(),
+        };
+        debug!("Listing directory at {path:?}");
+        list_directory(&self.mountpoint, &path, cursor, limit).await
+    }
+
+    async fn write_stream(
+        &self,
+        key: &StorageKey,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+    ) -> StorageResult<()> {
+        let path = resolve_parent(&self.mountpoint, key, true).await?;
+        debug!("Writing file at {path:?}");
+        write_file(&path, reader).await
+    }
+
+    async fn delete(&self, key: &StorageKey) -> StorageResult<()> {
+        let path = resolve_parent(&self.mountpoint, key, false).await?;
+        debug!("Deleting file at {path:?}");
+        delete_file(&path).await
+    }
+
+    async fn rename(&self, from: &StorageKey, to: &StorageKey) -> StorageResult<()> {
+        let from = resolve_parent(&self.mountpoint, from, false).await?;
+        let to = resolve_parent(&self.mountpoint, to, true).await?;
+        debug!("Moving file at {from:?} to {to:?}");
+        rename_file(&from, &to).await
+    }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        // A mount whose connection has died fails to stat, while one whose sshfs process has exited
+        // leaves behind a plain directory.
+        tokio::fs::metadata(&self.mountpoint).await?;
+        if !self.is_mounted() {
+            return Err(StorageError::Unavailable(
+                format!("{:?} is no longer an SSHFS mount", self.mountpoint).into(),
+            ));
         }
+        Ok(())
     }
 }
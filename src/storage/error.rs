@@ -0,0 +1,72 @@
+use core::fmt;
+use std::io;
+
+/// Errors that can be returned by any storage backend.
+///
+/// Backends are responsible for mapping their own errors into the most specific variant
+/// so callers can respond consistently regardless of where files are stored.
+#[derive(Debug)]
+pub enum StorageError {
+    /// Nothing exists at the requested key.
+    NotFound,
+    /// The backend refused access to the requested key.
+    Forbidden(Box<str>),
+    /// The requested key is not a valid storage key.
+    InvalidPath(Box<str>),
+    /// The backend could not be reached or is temporarily unable to serve requests.
+    Unavailable(Box<str>),
+    /// The backend did not respond in time.
+    Timeout(Box<str>),
+    /// Any other backend failure.
+    Backend(anyhow::Error),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound => f.write_str("File not found"),
+            StorageError::Forbidden(reason) => write!(f, "Access denied: {reason}"),
+            StorageError::InvalidPath(reason) => write!(f, "Invalid path: {reason}"),
+            StorageError::Unavailable(reason) => write!(f, "Storage unavailable: {reason}"),
+            StorageError::Timeout(reason) => write!(f, "Storage timed out: {reason}"),
+            StorageError::Backend(err) => write!(f, "Storage backend error: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => StorageError::NotFound,
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                StorageError::Forbidden(err.to_string().into())
+            }
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidFilename
+            | io::ErrorKind::IsADirectory => StorageError::InvalidPath(err.to_string().into()),
+            io::ErrorKind::TimedOut => StorageError::Timeout(err.to_string().into()),
+            io::ErrorKind::NotConnected
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::StaleNetworkFileHandle => {
+                StorageError::Unavailable(err.to_string().into())
+            }
+            _ => StorageError::Backend(err.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for StorageError {
+    fn from(err: anyhow::Error) -> Self {
+        StorageError::Backend(err)
+    }
+}
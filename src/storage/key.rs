@@ -0,0 +1,97 @@
+use super::{StorageError, StorageResult};
+use core::fmt;
+use std::path::Path;
+
+/// A validated, normalised key that identifies an object inside a storage backend.
+///
+/// Keys are always relative, `/`-separated and free of `.`/`..`/empty segments, NUL bytes
+/// and backslashes, so every backend can safely treat them as a path below its own root.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorageKey(Box<str>);
+
+impl StorageKey {
+    pub fn new(path: &str) -> StorageResult<Self> {
+        if path.contains('\0') {
+            return Err(invalid_path(format!(
+                "Paths cannot contain NUL bytes: {path:?}"
+            )));
+        }
+        if path.contains('\\') {
+            return Err(invalid_path(format!(
+                "Paths cannot contain backslashes: {path:?}"
+            )));
+        }
+        if path.starts_with('/') {
+            return Err(invalid_path(format!(
+                "Absolute paths are not allowed: {path:?}"
+            )));
+        }
+
+        let mut segments = Vec::new();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    return Err(invalid_path(format!(
+                        "Paths cannot reference a parent directory: {path:?}"
+                    )));
+                }
+                segment => segments.push(segment),
+            }
+        }
+        if segments.is_empty() {
+            return Err(invalid_path(format!("Paths cannot be empty: {path:?}")));
+        }
+
+        Ok(Self(segments.join("/").into_boxed_str()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&*self.0)
+    }
+}
+
+impl fmt::Display for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn invalid_path(reason: String) -> StorageError {
+    StorageError::InvalidPath(reason.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_normalised() {
+        assert_eq!(StorageKey::new("a/b.txt").unwrap().as_str(), "a/b.txt");
+        assert_eq!(StorageKey::new("a//./b/").unwrap().as_str(), "a/b");
+    }
+
+    #[test]
+    fn traversal_is_rejected() {
+        for path in [
+            "..",
+            "../etc/passwd",
+            "a/../../b",
+            "a/..",
+            "/etc/passwd",
+            "a\\..\\b",
+            "a\0b",
+            "",
+            "./.",
+        ] {
+            assert!(
+                matches!(StorageKey::new(path), Err(StorageError::InvalidPath(_))),
+                "{path:?}"
+            );
+        }
+    }
+}
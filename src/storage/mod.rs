@@ -1,20 +1,83 @@
 mod backends;
+mod error;
+mod key;
 
-use anyhow::Result;
+use crate::{metrics, request_context};
 use core::str::FromStr;
+pub use error::{StorageError, StorageResult};
+pub use key::StorageKey;
 use std::{
-    path::{Path, PathBuf},
+    ops::Range,
+    path::PathBuf,
     sync::Arc,
+    time::{Instant, SystemTime},
 };
 use tokio::io::AsyncRead;
+use tracing::{Instrument, field, info_span};
 
 pub struct FileMetadata {
     pub file_size: usize,
+    /// Whether the key refers to a directory (or an S3 prefix) rather than a file.
+    pub is_dir: bool,
+    /// The time the file was last modified, if known to the backend.
+    pub last_modified: Option<SystemTime>,
+    /// The entity tag of the file including its quotes and weak prefix, e.g. `"abc"` or `W/"abc"`.
+    pub etag: Option<Box<str>>,
+}
+
+pub struct DirectoryEntry {
+    /// The name of the entry relative to the listed directory, without any trailing slash.
+    pub name: Box<str>,
+    pub is_dir: bool,
+    pub file_size: usize,
+    pub last_modified: Option<SystemTime>,
+}
+
+pub struct DirectoryListing {
+    /// The entries on this page, ordered by name.
+    pub entries: Vec<DirectoryEntry>,
+    /// An opaque cursor that can be passed back to `list` to fetch the next page, if there is one.
+    pub next_cursor: Option<Box<str>>,
 }
 
 pub trait StorageOperations {
-    async fn read_stream(&self, path: &Path) -> Result<Option<Box<dyn AsyncRead + Unpin + Send>>>;
-    async fn metadata(&self, path: &Path) -> Result<Option<FileMetadata>>;
+    async fn read_stream(
+        &self,
+        key: &StorageKey,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>>;
+    /// Read the bytes within `range` (end-exclusive), which must already be clamped to the file size.
+    async fn read_range(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>>;
+    async fn metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata>;
+    /// The metadata of the file at `key`, treating directories as not found. Cheaper than
+    /// `metadata` when only files are of interest, e.g. when probing for index files.
+    async fn file_metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        match self.metadata(key).await? {
+            metadata if metadata.is_dir => Err(StorageError::NotFound),
+            metadata => Ok(metadata),
+        }
+    }
+    /// List up to `limit` entries of the directory at `key`, or the storage root if `key` is `None`.
+    async fn list(
+        &self,
+        key: Option<&StorageKey>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<DirectoryListing>;
+    /// Write the contents of `reader` to `key`, replacing any existing file only once fully written.
+    async fn write_stream(
+        &self,
+        key: &StorageKey,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+    ) -> StorageResult<()>;
+    async fn delete(&self, key: &StorageKey) -> StorageResult<()>;
+    /// Move the file at `from` to `to`, replacing any existing file at `to`.
+    async fn rename(&self, from: &StorageKey, to: &StorageKey) -> StorageResult<()>;
+    /// Check that the backend is reachable and able to serve requests.
+    async fn health_check(&self) -> StorageResult<()>;
 }
 
 #[derive(Debug, Clone)]
@@ -27,40 +90,252 @@ pub enum StorageBackend {
     Sshfs(Arc<backends::SSHFSStorage>),
 }
 
-impl StorageOperations for StorageBackend {
-    async fn read_stream(&self, path: &Path) -> Result<Option<Box<dyn AsyncRead + Unpin + Send>>> {
+impl StorageBackend {
+    /// The name of the backend variant, used to label metrics.
+    pub fn name(&self) -> &'static str {
         match self {
             #[cfg(feature = "storage-filesystem")]
-            StorageBackend::Filesystem(storage) => storage.read_stream(path).await,
+            StorageBackend::Filesystem(_) => "filesystem",
             #[cfg(feature = "storage-s3")]
-            StorageBackend::S3(storage) => storage.read_stream(path).await,
+            StorageBackend::S3(_) => "s3",
+            #[cfg(feature = "storage-sshfs")]
+            StorageBackend::Sshfs(_) => "sshfs",
+        }
+    }
+
+    /// Whether the backend's remote filesystem is mounted, or `None` if it doesn't mount one.
+    pub fn is_mounted(&self) -> Option<bool> {
+        match self {
             #[cfg(feature = "storage-sshfs")]
-            StorageBackend::Sshfs(storage) => storage.read_stream(path).await,
+            StorageBackend::Sshfs(storage) => Some(storage.is_mounted()),
+            #[allow(unreachable_patterns)]
+            _ => None,
         }
     }
 
-    async fn metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
+    /// Run a storage operation in its own span carrying the key and where it is stored, timing it
+    /// for metrics and the request's `Server-Timing`, and marking the span as failed if it returns
+    /// an error other than the file not existing.
+    async fn instrument<T>(
+        &self,
+        operation: &'static str,
+        key: Option<&StorageKey>,
+        future: impl Future<Output = StorageResult<T>>,
+    ) -> StorageResult<T> {
+        let span = info_span!(
+            "storage",
+            otel.name = operation,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            backend = self.name(),
+            key = key.map(StorageKey::as_str),
+            bucket = field::Empty,
+            path = field::Empty,
+            destination = field::Empty,
+            error = field::Empty,
+        );
         match self {
             #[cfg(feature = "storage-filesystem")]
-            StorageBackend::Filesystem(storage) => storage.metadata(path).await,
+            StorageBackend::Filesystem(storage) => {
+                let path = key.map_or_else(
+                    || storage.base_path().to_path_buf(),
+                    |key| storage.base_path().join(key.as_str()),
+                );
+                span.record("path", field::display(path.display()));
+            }
             #[cfg(feature = "storage-s3")]
-            StorageBackend::S3(storage) => storage.metadata(path).await,
+            StorageBackend::S3(storage) => {
+                span.record("bucket", storage.bucket());
+            }
             #[cfg(feature = "storage-sshfs")]
-            StorageBackend::Sshfs(storage) => storage.metadata(path).await,
+            StorageBackend::Sshfs(storage) => {
+                let path = key.map_or_else(
+                    || storage.mountpoint().to_path_buf(),
+                    |key| storage.mountpoint().join(key.as_str()),
+                );
+                span.record("path", field::display(path.display()));
+            }
+        }
+        let start = Instant::now();
+        let result = future.instrument(span.clone()).await;
+        let duration = start.elapsed();
+        metrics::observe_storage(self.name(), operation, duration);
+        request_context::record_storage(operation, duration);
+        if let Err(err) = &result
+            && !matches!(err, StorageError::NotFound)
+        {
+            span.record("otel.status_code", "ERROR");
+            span.record("error", field::display(err));
         }
+        result
+    }
+}
+
+impl StorageOperations for StorageBackend {
+    async fn read_stream(
+        &self,
+        key: &StorageKey,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        self.instrument("read_stream", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.read_stream(key).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.read_stream(key).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.read_stream(key).await,
+            }
+        })
+        .await
+    }
+
+    async fn read_range(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+    ) -> StorageResult<Box<dyn AsyncRead + Unpin + Send>> {
+        self.instrument("read_range", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.read_range(key, range).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.read_range(key, range).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.read_range(key, range).await,
+            }
+        })
+        .await
+    }
+
+    async fn metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        self.instrument("metadata", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.metadata(key).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.metadata(key).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.metadata(key).await,
+            }
+        })
+        .await
+    }
+
+    async fn file_metadata(&self, key: &StorageKey) -> StorageResult<FileMetadata> {
+        self.instrument("metadata", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.file_metadata(key).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.file_metadata(key).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.file_metadata(key).await,
+            }
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        key: Option<&StorageKey>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<DirectoryListing> {
+        self.instrument("list", key, async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.list(key, cursor, limit).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.list(key, cursor, limit).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.list(key, cursor, limit).await,
+            }
+        })
+        .await
+    }
+
+    async fn write_stream(
+        &self,
+        key: &StorageKey,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+    ) -> StorageResult<()> {
+        self.instrument("write_stream", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.write_stream(key, reader).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.write_stream(key, reader).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.write_stream(key, reader).await,
+            }
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &StorageKey) -> StorageResult<()> {
+        self.instrument("delete", Some(key), async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.delete(key).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.delete(key).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.delete(key).await,
+            }
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &StorageKey, to: &StorageKey) -> StorageResult<()> {
+        self.instrument("rename", Some(from), async {
+            tracing::Span::current().record("destination", to.as_str());
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.rename(from, to).await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.rename(from, to).await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.rename(from, to).await,
+            }
+        })
+        .await
+    }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        self.instrument("health_check", None, async {
+            match self {
+                #[cfg(feature = "storage-filesystem")]
+                StorageBackend::Filesystem(storage) => storage.health_check().await,
+                #[cfg(feature = "storage-s3")]
+                StorageBackend::S3(storage) => storage.health_check().await,
+                #[cfg(feature = "storage-sshfs")]
+                StorageBackend::Sshfs(storage) => storage.health_check().await,
+            }
+        })
+        .await
     }
 }
 
 impl FromStr for StorageBackend {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        Self::open(s, true)
+    }
+}
+
+impl StorageBackend {
+    /// Set up the storage backend at `url`, creating its directory or bucket if it doesn't exist
+    /// and `create` is set.
+    pub fn open(url: &str, create: bool) -> Result<Self, String> {
+        match url {
             #[cfg(feature = "storage-filesystem")]
-            _ if s.starts_with("fs://") => {
+            _ if url.starts_with("fs://") => {
                 use faccess::{AccessMode, PathExt};
 
-                let fs_path = PathBuf::from(s.trim_start_matches("fs://").trim());
-                let _ = std::fs::create_dir_all(&fs_path);
+                let fs_path = PathBuf::from(url.trim_start_matches("fs://").trim());
+                if create {
+                    let _ = std::fs::create_dir_all(&fs_path);
+                }
                 if let Err(err) = fs_path.access(AccessMode::WRITE | AccessMode::READ) {
                     return Err(format!(
                         "Path specified cannot be read from or written to by the current user\n\nError: {err}"
@@ -73,24 +348,32 @@ impl FromStr for StorageBackend {
             }
 
             #[cfg(feature = "storage-s3")]
-            _ if s.starts_with("s3://") => {
-                let bucket = s
+            _ if url.starts_with("s3://") => {
+                let (bucket, prefix) = url
                     .trim_start_matches("s3://")
-                    .split('/')
-                    .next()
-                    .ok_or("S3 URL must include bucket: s3://bucket")?;
+                    .split_once('/')
+                    .unwrap_or((url.trim_start_matches("s3://"), ""));
                 if bucket.is_empty() {
                     return Err("S3 bucket name cannot be empty".to_string());
                 }
+                // Files can be served from beneath a prefix of the bucket, e.g. `s3://bucket/site/`.
+                let prefix = match prefix.trim_matches('/') {
+                    "" => String::new(),
+                    prefix => format!(
+                        "{}/",
+                        StorageKey::new(prefix)
+                            .map_err(|err| format!("Invalid S3 prefix: {err}"))?
+                    ),
+                };
                 Ok(Self::S3(Arc::new(
-                    backends::S3Storage::new(bucket)
+                    backends::S3Storage::new(bucket, prefix, create)
                         .map_err(|err| format!("failed to create S3 client: {err:?}"))?,
                 )))
             }
 
             #[cfg(feature = "storage-sshfs")]
-            _ if s.starts_with("sshfs://") => {
-                let mountpoint = s.trim_start_matches("sshfs://").trim().to_string();
+            _ if url.starts_with("sshfs://") => {
+                let mountpoint = url.trim_start_matches("sshfs://").trim().to_string();
                 if mountpoint.is_empty() {
                     return Err("SSHFS mountpoint cannot be empty".to_string());
                 }
@@ -105,7 +388,7 @@ impl FromStr for StorageBackend {
                 #[cfg(feature = "storage-filesystem")]
                 valid_sources.push("'fs://path'");
                 #[cfg(feature = "storage-s3")]
-                valid_sources.push("'s3://bucket'");
+                valid_sources.push("'s3://bucket[/prefix]'");
                 #[cfg(feature = "storage-sshfs")]
                 valid_sources.push("'sshfs://mountpoint'");
 
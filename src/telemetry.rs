@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response as AxumResponse,
+};
+use clap::ValueEnum;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceContextExt, TracerProvider},
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, registry::LookupSpan};
+
+/// The protocol spans are exported to an OpenTelemetry collector over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OtelProtocol {
+    /// OTLP over gRPC, usually on port 4317.
+    Grpc,
+    /// OTLP over HTTP with protobuf bodies, usually on port 4318.
+    Http,
+}
+
+/// Create a tracer provider that exports spans in batches to the OTLP collector at `endpoint`, and
+/// continue traces from incoming W3C `traceparent` headers.
+pub fn tracer_provider(
+    endpoint: &str,
+    protocol: OtelProtocol,
+    service_name: &str,
+) -> Result<SdkTracerProvider> {
+    let exporter = match protocol {
+        OtelProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        // Unlike gRPC, the HTTP exporter expects the full URL of the traces endpoint.
+        OtelProtocol::Http => {
+            let endpoint = match endpoint.trim_end_matches('/') {
+                endpoint if endpoint.ends_with("/v1/traces") => endpoint.to_owned(),
+                endpoint => format!("{endpoint}/v1/traces"),
+            };
+            SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+        }
+    }
+    .context("Failed to create OTLP span exporter")?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_owned())
+                .build(),
+        )
+        .build())
+}
+
+/// A layer that exports `tracing` spans through `provider`.
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S> + use<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+/// Make `span` a child of the span the client sent in its `traceparent` header, if there is one.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(parent);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Middleware that tells clients which trace their request was recorded in through a
+/// `traceresponse` header, in the same format as `traceparent`.
+pub async fn trace_response(req: Request, next: Next) -> AxumResponse {
+    let mut res = next.run(req).await;
+    let context = Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    if span_context.is_valid() {
+        let value = format!(
+            "00-{}-{}-{:02x}",
+            span_context.trace_id(),
+            span_context.span_id(),
+            span_context.trace_flags().to_u8()
+        );
+        if let Ok(value) = HeaderValue::try_from(value) {
+            res.headers_mut().insert("traceresponse", value);
+        }
+    }
+    res
+}
@@ -0,0 +1,315 @@
+use crate::{
+    mounts::{Mount, Mounts},
+    storage::{StorageBackend, StorageError, StorageOperations},
+};
+use anyhow::{Context, Result};
+use axum::Router;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{debug, info, warn};
+
+/// How long a tenant that couldn't be opened is remembered before opening it is tried again.
+const MISSING_TTL: Duration = Duration::from_secs(30);
+
+/// The number of tenants that may be opened at once for each site.
+const MAX_OPENING: usize = 16;
+
+/// A storage backend URL with `{placeholder}`s that are filled in for each tenant, such as
+/// `s3://sites/{tenant}/`.
+#[derive(Debug, Clone)]
+pub struct StorageTemplate {
+    url: Box<str>,
+    placeholders: Vec<Box<str>>,
+}
+
+impl StorageTemplate {
+    /// Parse a storage backend URL, returning `None` if it has no placeholders.
+    pub fn parse(url: &str) -> Result<Option<Self>, String> {
+        let mut placeholders = Vec::new();
+        let mut rest = url;
+        while let Some(start) = rest.find(['{', '}']) {
+            let name = rest[start..]
+                .strip_prefix('{')
+                .and_then(|name| name.split_once('}'))
+                .map(|(name, _)| name)
+                .filter(|name| is_placeholder_name(name))
+                .ok_or_else(|| {
+                    format!("invalid placeholder in {url:?}, expected a name such as `{{tenant}}`")
+                })?;
+            placeholders.push(name.into());
+            rest = &rest[start + name.len() + 2..];
+        }
+        if placeholders.is_empty() {
+            return Ok(None);
+        }
+        placeholders.sort();
+        placeholders.dedup();
+        Ok(Some(Self {
+            url: url.into(),
+            placeholders,
+        }))
+    }
+
+    /// The names of the placeholders in the template.
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.placeholders.iter().map(|name| &**name)
+    }
+
+    /// Open the storage backend of a tenant, without creating its directory or bucket.
+    fn open(&self, placeholder: &str, tenant: &str) -> Result<StorageBackend, String> {
+        StorageBackend::open(
+            &self.url.replace(&format!("{{{placeholder}}}"), tenant),
+            false,
+        )
+    }
+}
+
+/// Whether a placeholder name is made up of lowercase letters, digits and underscores.
+pub fn is_placeholder_name(name: &str) -> bool {
+    name.starts_with(|char: char| char.is_ascii_lowercase() || char == '_')
+        && name
+            .chars()
+            .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '_')
+}
+
+/// Whether a tenant name taken from a hostname is safe to substitute into a storage backend URL:
+/// a single DNS label of lowercase letters, digits and inner hyphens.
+pub fn is_tenant_name(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '-')
+}
+
+/// Where a mount of a tenant's site serves files from.
+pub enum TenantStorage {
+    /// A storage backend shared by every tenant.
+    Shared(StorageBackend),
+    /// A storage backend opened for each tenant.
+    Template(StorageTemplate),
+}
+
+/// A mount of a site serving many tenants.
+pub struct TenantMount {
+    pub prefix: String,
+    pub storage: TenantStorage,
+    pub file_cache_duration: Option<Duration>,
+    pub file_stream_buffersize: usize,
+}
+
+/// A site serving a tenant for each subdomain, from storage backends that are opened the first time
+/// the tenant is requested and kept open for every later request. Subdomains without a tenant are
+/// remembered for `MISSING_TTL`.
+pub struct Tenants {
+    name: Arc<str>,
+    placeholder: Box<str>,
+    mounts: Vec<TenantMount>,
+    router: Box<dyn Fn(Arc<Mounts>) -> Router + Send + Sync>,
+    tenants: Mutex<TenantCache>,
+    opening: Semaphore,
+}
+
+/// The tenants that have been or are being opened, by name.
+#[derive(Default)]
+struct TenantCache {
+    tenants: HashMap<Box<str>, Arc<OnceCell<Opened>>>,
+    /// The number of tenants after which expired ones are pruned.
+    prune_at: usize,
+}
+
+/// The outcome of opening a tenant, shared by every request that waited for it.
+struct Opened {
+    router: Option<Router>,
+    at: Instant,
+}
+
+impl Opened {
+    fn is_expired(&self) -> bool {
+        self.router.is_none() && self.at.elapsed() >= MISSING_TTL
+    }
+}
+
+impl TenantCache {
+    /// The entry of a tenant, replacing it if the tenant was missing long enough ago to try again.
+    fn entry(&mut self, tenant: &str) -> Arc<OnceCell<Opened>> {
+        let expired = |cell: &OnceCell<Opened>| cell.get().is_some_and(Opened::is_expired);
+        if let Some(cell) = self.tenants.get(tenant)
+            && !expired(cell)
+        {
+            return cell.clone();
+        }
+        // Pruning whenever the number of tenants has doubled keeps it proportional to the number
+        // of tenants that exist or were recently requested.
+        if self.tenants.len() >= self.prune_at {
+            self.tenants.retain(|_, cell| !expired(cell));
+            self.prune_at = (self.tenants.len() * 2).max(64);
+        }
+        let cell = Arc::new(OnceCell::new());
+        self.tenants.insert(tenant.into(), cell.clone());
+        cell
+    }
+}
+
+impl Tenants {
+    /// Serve the tenants of a site, building the router of each one from its mounts with `router`.
+    pub fn new(
+        name: &str,
+        placeholder: &str,
+        mounts: Vec<TenantMount>,
+        router: impl Fn(Arc<Mounts>) -> Router + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            placeholder: placeholder.into(),
+            mounts,
+            router: Box::new(router),
+            tenants: Mutex::default(),
+            opening: Semaphore::new(MAX_OPENING),
+        }
+    }
+
+    /// The router serving a tenant, or `None` if the tenant has no files. Concurrent requests for
+    /// a tenant that isn't open yet wait for the same attempt to open it.
+    pub async fn router(&self, tenant: &str) -> Option<Router> {
+        let cell = self
+            .tenants
+            .lock()
+            .expect("tenants lock poisoned")
+            .entry(tenant);
+        let opened = cell
+            .get_or_init(|| async {
+                Opened {
+                    router: self.load(tenant).await,
+                    at: Instant::now(),
+                }
+            })
+            .await;
+        opened.router.clone()
+    }
+
+    /// Open a tenant and build its router.
+    async fn load(&self, tenant: &str) -> Option<Router> {
+        let _permit = self
+            .opening
+            .acquire()
+            .await
+            .expect("the opening semaphore is never closed");
+        let mounts = match self.open(tenant).await {
+            Ok(Some(mounts)) => mounts,
+            Ok(None) => {
+                debug!("Site {:?} has no tenant {tenant:?}", self.name);
+                return None;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to open storage of tenant {tenant:?} of site {:?}: {err:#}",
+                    self.name
+                );
+                return None;
+            }
+        };
+        info!("Serving tenant {tenant:?} of site {:?}", self.name);
+        Some((self.router)(Arc::new(mounts)))
+    }
+
+    /// Open the storage backends of a tenant, checking that every one that is specific to the
+    /// tenant already has files so subdomains without a tenant don't create any.
+    async fn open(&self, tenant: &str) -> Result<Option<Mounts>> {
+        let mut mounts = Vec::new();
+        for mount in &self.mounts {
+            let storage = match &mount.storage {
+                TenantStorage::Shared(storage) => storage.clone(),
+                TenantStorage::Template(template) => {
+                    // Opening some backends blocks until they have connected.
+                    let storage = tokio::task::spawn_blocking({
+                        let (template, placeholder, tenant) = (
+                            template.clone(),
+                            self.placeholder.clone(),
+                            tenant.to_owned(),
+                        );
+                        move || template.open(&placeholder, &tenant)
+                    })
+                    .await?;
+                    // Tenants whose directory or bucket doesn't exist can't be opened.
+                    let storage = match storage {
+                        Ok(storage) => storage,
+                        Err(err) => {
+                            debug!("Failed to open storage of tenant {tenant:?}: {err}");
+                            return Ok(None);
+                        }
+                    };
+                    match storage.list(None, None, 1).await {
+                        Ok(listing) if !listing.entries.is_empty() => {}
+                        Ok(_) | Err(StorageError::NotFound) => return Ok(None),
+                        Err(err) => return Err(err).context("Failed to list files"),
+                    }
+                    storage
+                }
+            };
+            mounts.push(Mount::new(
+                &mount.prefix,
+                storage,
+                mount.file_cache_duration,
+                mount.file_stream_buffersize,
+            ));
+        }
+        Ok(Some(Mounts::new(mounts)))
+    }
+}
+
+#[cfg(all(test, feature = "storage-filesystem"))]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Tenants served from directories beneath a temporary directory with a single tenant, `acme`,
+    /// counting the routers built. The directory is removed when the returned one is dropped.
+    fn tenants(name: &str) -> (Tenants, Arc<AtomicUsize>, TempDir) {
+        let dir = TempDir::new(&format!("tenants-{name}"));
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("acme")).unwrap();
+        std::fs::write(root.join("acme/index.html"), "acme").unwrap();
+        let template = StorageTemplate::parse(&format!("fs://{}/{{tenant}}", root.display()))
+            .unwrap()
+            .unwrap();
+        let mounts = vec![TenantMount {
+            prefix: String::new(),
+            storage: TenantStorage::Template(template),
+            file_cache_duration: None,
+            file_stream_buffersize: 1024,
+        }];
+        let built = Arc::new(AtomicUsize::new(0));
+        let tenants = Tenants::new("sites", "tenant", mounts, {
+            let built = built.clone();
+            move |_| {
+                built.fetch_add(1, Ordering::Relaxed);
+                Router::new()
+            }
+        });
+        (tenants, built, dir)
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_open_a_tenant_once() {
+        let (tenants, built, _dir) = tenants("concurrent");
+        let routers = futures_util::future::join_all((0..8).map(|_| tenants.router("acme"))).await;
+        assert!(routers.iter().all(Option::is_some));
+        assert_eq!(built.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_tenants_are_remembered() {
+        let (tenants, _, _dir) = tenants("missing");
+        assert!(tenants.router("nobody").await.is_none());
+        let cache = tenants.tenants.lock().unwrap();
+        let opened = cache.tenants["nobody"].get().unwrap();
+        assert!(opened.router.is_none() && !opened.is_expired());
+    }
+}
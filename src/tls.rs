@@ -0,0 +1,258 @@
+use crate::hosts::{HostPattern, misdirected, request_host};
+use anyhow::{Context, Result, bail};
+use axum::{
+    Router,
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use clap::ValueEnum;
+use core::{cmp::Reverse, str::FromStr};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A PEM certificate chain and the private key it was issued for.
+#[derive(Debug, Clone)]
+pub struct CertificatePair {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl FromStr for CertificatePair {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cert, key) = s
+            .trim()
+            .split_once(':')
+            .ok_or("Certificates must be in the form '<cert path>:<key path>'")?;
+        Ok(Self {
+            cert: cert.into(),
+            key: key.into(),
+        })
+    }
+}
+
+/// Whether clients must present a certificate signed by the client CA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClientAuth {
+    Optional,
+    Required,
+}
+
+/// Picks the first certificate valid for the hostname a client requested through SNI, falling back
+/// to the first certificate if none are.
+#[derive(Debug)]
+struct CertificateResolver(Box<[Arc<CertifiedKey>]>);
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello
+            .server_name()
+            .and_then(|name| ServerName::try_from(name).ok());
+        let matching = name.and_then(|name| {
+            self.0.iter().find(|certified| {
+                certified
+                    .end_entity_cert()
+                    .ok()
+                    .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+                    .is_some_and(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok())
+            })
+        });
+        matching.or_else(|| self.0.first()).cloned()
+    }
+}
+
+/// The TLS configuration for the HTTPS listener, reloaded whenever its certificate files change.
+pub struct TlsConfig {
+    certificates: Box<[CertificatePair]>,
+    client_ca: Option<PathBuf>,
+    client_auth: ClientAuth,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsConfig {
+    /// Load the configured certificates, returning `None` if TLS isn't enabled.
+    pub fn new(
+        certificates: Vec<CertificatePair>,
+        client_ca: Option<PathBuf>,
+        client_auth: ClientAuth,
+    ) -> Result<Option<Arc<Self>>> {
+        if certificates.is_empty() {
+            if client_ca.is_some() {
+                bail!(
+                    "Client certificates can only be verified when TLS certificates are configured"
+                );
+            }
+            return Ok(None);
+        }
+        let current = build_server_config(&certificates, client_ca.as_deref(), client_auth)?;
+        Ok(Some(Arc::new(Self {
+            certificates: certificates.into(),
+            client_ca,
+            client_auth,
+            current: RwLock::new(Arc::new(current)),
+        })))
+    }
+
+    /// An acceptor for the current configuration.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Periodically reload the certificate files in the background whenever they change, so renewed
+    /// certificates are picked up by new connections without a restart.
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let config = self.clone();
+        tokio::spawn(async move {
+            let mut stamps = config.file_stamps().await;
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let current = config.file_stamps().await;
+                if current == stamps {
+                    continue;
+                }
+                stamps = current;
+                let loaded = build_server_config(
+                    &config.certificates,
+                    config.client_ca.as_deref(),
+                    config.client_auth,
+                );
+                match loaded {
+                    Ok(loaded) => {
+                        *config.current.write().unwrap() = Arc::new(loaded);
+                        info!("Reloaded TLS certificates");
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Failed to reload TLS certificates, keeping the previous ones: {err:#}"
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    async fn file_stamps(&self) -> Vec<Option<(SystemTime, u64)>> {
+        async fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+            let metadata = tokio::fs::metadata(path).await.ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        }
+        let paths = self
+            .certificates
+            .iter()
+            .flat_map(|pair| [&pair.cert, &pair.key])
+            .chain(&self.client_ca);
+        let mut stamps = Vec::new();
+        for path in paths {
+            stamps.push(stamp(path).await);
+        }
+        stamps
+    }
+}
+
+fn build_server_config(
+    certificates: &[CertificatePair],
+    client_ca: Option<&Path>,
+    client_auth: ClientAuth,
+) -> Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let certificates = certificates
+        .iter()
+        .map(|pair| load_certified_key(pair, &provider).map(Arc::new))
+        .collect::<Result<Box<[_]>>>()?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certificates(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid client CA certificate in {path:?}"))?;
+            }
+            let mut verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if client_auth == ClientAuth::Optional {
+                verifier = verifier.allow_unauthenticated();
+            }
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::new(CertificateResolver(certificates)));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("Failed to read certificates from {path:?}"))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {path:?}");
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(pair: &CertificatePair, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let chain = read_certificates(&pair.cert)?;
+    let key = PrivateKeyDer::from_pem_file(&pair.key)
+        .with_context(|| format!("Failed to read private key from {:?}", pair.key))?;
+    CertifiedKey::from_der(chain, key, provider)
+        .with_context(|| format!("Invalid certificate or key in {:?}", pair.cert))
+}
+
+/// The common name of a verified client certificate's subject, used as the client's identity.
+pub fn client_subject(certificates: Option<&[CertificateDer<'_>]>) -> Option<Arc<str>> {
+    let (_, cert) = X509Certificate::from_der(certificates?.first()?).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.into())
+}
+
+/// A router that permanently redirects every request to the same URL over HTTPS, on the port of
+/// the listener serving the request's host. Each host is listed with the port serving it, or
+/// `None` for the ports serving hosts no other site serves.
+pub fn redirect_router(ports: Vec<(Option<HostPattern>, u16)>) -> Router {
+    let ports = Arc::new(ports);
+    Router::new().fallback(async move |req: Request| -> Response {
+        let Some(host) = request_host(&req) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        // Listeners are chosen between the same way as sites sharing one, falling back to those
+        // serving unmatched hosts.
+        let port = ports
+            .iter()
+            .filter_map(|(pattern, port)| match pattern {
+                Some(pattern) if pattern.matches(&host) => Some((pattern.precedence(), *port)),
+                Some(_) => None,
+                None => Some(((u8::MAX, Reverse(0)), *port)),
+            })
+            .min();
+        let Some((_, port)) = port else {
+            return misdirected(req.headers());
+        };
+        let authority = match port {
+            443 => host,
+            port => format!("{host}:{port}"),
+        };
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        Redirect::permanent(&format!("https://{authority}{path}")).into_response()
+    })
+}